@@ -0,0 +1,117 @@
+use std::env;
+use std::net::SocketAddr;
+
+use bravery::{
+    error_500, App, AsyncHandler, EmptyState, HandlerFuture, HttpError, Request, Response,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+extern crate serde;
+extern crate serde_json;
+
+#[macro_use]
+extern crate serde_derive;
+
+#[derive(Serialize, Clone)]
+struct User {
+    id: u32,
+    name: String,
+}
+
+// Stands in for a database client: every lookup is a future
+struct UserRepository {
+    users: Vec<User>,
+}
+impl UserRepository {
+    async fn find(&self, id: u32) -> Option<User> {
+        futures::future::ready(self.users.iter().find(|u| u.id == id).cloned()).await
+    }
+}
+
+#[derive(Clone)]
+struct UserHandler {
+    repository: Arc<UserRepository>,
+}
+impl AsyncHandler<EmptyState> for UserHandler {
+    fn invoke(&self, req: Request<EmptyState>) -> HandlerFuture {
+        let repository = self.repository.clone();
+        Box::pin(async move {
+            let id: u32 = req
+                .param("id")
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| HttpError {
+                    status_code: 400,
+                    error_message: "Invalid id".to_string(),
+                    details: "".to_string(),
+                })?;
+
+            let user = repository.find(id).await.ok_or_else(|| HttpError {
+                status_code: 404,
+                error_message: "Not found".to_string(),
+                details: "".to_string(),
+            })?;
+
+            let val = serde_json::to_vec(&user).map_err(error_500("Unable to serialize"))?;
+
+            Ok(Response {
+                status_code: 200,
+                reason_phrase: None,
+                content_type: Some("application/json".to_string()),
+                body: val,
+                headers: HashMap::new(),
+                stream: None,
+            })
+        })
+    }
+}
+
+fn get_app() -> App<EmptyState> {
+    let repository = UserRepository {
+        users: vec![User {
+            id: 1,
+            name: "Tommaso".to_string(),
+        }],
+    };
+
+    let mut app: App<EmptyState> = Default::default();
+    app.get(
+        "/users/:id",
+        Box::new(UserHandler {
+            repository: Arc::new(repository),
+        }),
+    );
+    app
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8880".to_string());
+    let addr = addr.parse::<SocketAddr>()?;
+
+    get_app().run(addr)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn async_handler() {
+        let app = get_app();
+
+        let request = app.create_request("GET", "/users/1", "", b"".to_vec());
+        let response = app.inject(request);
+
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, br#"{"id":1,"name":"Tommaso"}"#.to_vec());
+
+        let request = app.create_request("GET", "/users/2", "", b"".to_vec());
+        let response = app.inject(request);
+
+        assert_eq!(response.status_code, 404);
+    }
+}
@@ -1,8 +1,7 @@
 use std::env;
 use std::net::SocketAddr;
 
-use bravery::{error_500, App, EmptyState, Handler, HttpError, Request, Response};
-use std::collections::HashMap;
+use bravery::{App, EmptyState, Json, Request};
 
 extern crate serde;
 extern crate serde_json;
@@ -15,28 +14,15 @@ struct JsonStruct<'a> {
     message: &'a str,
 }
 
-#[derive(Clone)]
-struct TestHandler {}
-impl Handler<EmptyState> for TestHandler {
-    fn invoke(&self, _req: Request<EmptyState>) -> Result<Response, HttpError> {
-        let json = JsonStruct {
-            message: "Hello, World!",
-        };
-
-        let val = serde_json::to_vec(&json).map_err(error_500("Unable to serialize"))?;
-
-        Ok(Response {
-            status_code: 200,
-            content_type: Some("application/json".to_string()),
-            body: val,
-            headers: HashMap::new(),
-        })
-    }
+fn hello_world(_req: Request<EmptyState>) -> Json<JsonStruct<'static>> {
+    Json(JsonStruct {
+        message: "Hello, World!",
+    })
 }
 
 fn get_app() -> App<EmptyState> {
     let mut app: App<EmptyState> = Default::default();
-    app.get("/", Box::new(TestHandler {}));
+    app.get("/", hello_world);
     app
 }
 
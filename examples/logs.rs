@@ -40,9 +40,11 @@ impl Handler<EmptyState> for TestHandler {
         info!(req.logger, "formatted: {}", 1; "wow" => params);
         Ok(Response {
             status_code: 200,
+            reason_phrase: None,
             content_type: Some("application/json".to_string()),
             body: "Ok".to_string().into_bytes(),
             headers: HashMap::new(),
+            stream: None,
         })
     }
 }
@@ -1,7 +1,7 @@
 use std::env;
 use std::net::SocketAddr;
 
-use bravery::{error_400, error_500, App, EmptyState, Handler, HttpError, Request, Response};
+use bravery::{error_500, App, EmptyState, HttpError, Json, Response};
 use std::collections::HashMap;
 
 extern crate serde;
@@ -16,36 +16,30 @@ struct JsonStruct<'a> {
 }
 
 #[derive(Serialize, Deserialize)]
-struct MyBody<'a> {
-    pub message: &'a str,
+struct MyBody {
+    pub message: String,
 }
 
-#[derive(Clone)]
-struct TestHandler {}
-impl Handler<EmptyState> for TestHandler {
-    fn invoke(&self, req: Request<EmptyState>) -> Result<Response, HttpError> {
-        let body: MyBody = req
-            .body_as()
-            .map_err(error_400("Unable to deserialize body"))?;
-
-        let json = JsonStruct {
-            message: body.message,
-        };
-
-        let val = serde_json::to_vec(&json).map_err(error_500("Unable to serialize"))?;
-
-        Ok(Response {
-            status_code: 200,
-            content_type: Some("application/json".to_string()),
-            body: val,
-            headers: HashMap::new(),
-        })
-    }
+fn post_body(Json(body): Json<MyBody>) -> Result<Response, HttpError> {
+    let json = JsonStruct {
+        message: &body.message,
+    };
+
+    let val = serde_json::to_vec(&json).map_err(error_500("Unable to serialize"))?;
+
+    Ok(Response {
+        status_code: 200,
+        reason_phrase: None,
+        content_type: Some("application/json".to_string()),
+        body: val,
+        headers: HashMap::new(),
+        stream: None,
+    })
 }
 
 fn get_app() -> App<EmptyState> {
     let mut app: App<EmptyState> = Default::default();
-    app.post("/", Box::new(TestHandler {}));
+    app.post("/", post_body);
     app
 }
 
@@ -69,7 +63,7 @@ mod tests {
         let app = get_app();
 
         let body = serde_json::to_string(&MyBody {
-            message: "my_message",
+            message: "my_message".to_string(),
         })
         .unwrap();
         let request = app.create_request("POST", "/", "", body.as_bytes().to_vec());
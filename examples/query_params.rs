@@ -1,7 +1,7 @@
 use std::env;
 use std::net::SocketAddr;
 
-use bravery::{error_400, error_500, App, EmptyState, Handler, HttpError, Request, Response};
+use bravery::{error_500, App, EmptyState, HttpError, Query, Response};
 use std::collections::HashMap;
 
 extern crate serde;
@@ -19,32 +19,26 @@ struct MyParams {
     pub message: String,
 }
 
-#[derive(Clone)]
-struct TestHandler {}
-impl Handler<EmptyState> for TestHandler {
-    fn invoke(&self, req: Request<EmptyState>) -> Result<Response, HttpError> {
-        let params: MyParams = req
-            .query_string_as()
-            .map_err(error_400("Unable to deserialize query_params"))?;
-
-        let json = JsonStruct {
-            message: &params.message,
-        };
-
-        let val = serde_json::to_vec(&json).map_err(error_500("Unable to serialize"))?;
-
-        Ok(Response {
-            status_code: 200,
-            content_type: Some("application/json".to_string()),
-            body: val,
-            headers: HashMap::new(),
-        })
-    }
+fn query_params(Query(params): Query<MyParams>) -> Result<Response, HttpError> {
+    let json = JsonStruct {
+        message: &params.message,
+    };
+
+    let val = serde_json::to_vec(&json).map_err(error_500("Unable to serialize"))?;
+
+    Ok(Response {
+        status_code: 200,
+        reason_phrase: None,
+        content_type: Some("application/json".to_string()),
+        body: val,
+        headers: HashMap::new(),
+        stream: None,
+    })
 }
 
 fn get_app() -> App<EmptyState> {
     let mut app: App<EmptyState> = Default::default();
-    app.get("/", Box::new(TestHandler {}));
+    app.get("/", query_params);
     app
 }
 
@@ -45,9 +45,11 @@ impl Handler<Arc<Mutex<MyState>>> for TestHandler {
 
         Ok(Response {
             status_code: 200,
+            reason_phrase: None,
             content_type: Some("application/json".to_string()),
             body: val,
             headers: HashMap::new(),
+            stream: None,
         })
     }
 }
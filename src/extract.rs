@@ -0,0 +1,77 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::request::Request;
+use crate::{error_400, HttpError};
+
+/// Builds a handler argument out of the incoming request.
+/// A failure stops the request before the handler runs
+pub trait FromRequest<T: Clone + Send + Sync>: Sized {
+    fn from_request(req: &Request<T>) -> Result<Self, HttpError>;
+}
+
+/// The request body deserialized from JSON
+pub struct Json<B>(pub B);
+
+impl<T: Clone + Send + Sync, B: DeserializeOwned> FromRequest<T> for Json<B> {
+    fn from_request(req: &Request<T>) -> Result<Self, HttpError> {
+        req.body_as()
+            .map(Json)
+            .map_err(error_400("Unable to deserialize body"))
+    }
+}
+
+/// The query string deserialized with `serde_urlencoded`
+pub struct Query<Q>(pub Q);
+
+impl<T: Clone + Send + Sync, Q: DeserializeOwned> FromRequest<T> for Query<Q> {
+    fn from_request(req: &Request<T>) -> Result<Self, HttpError> {
+        req.query_string_as()
+            .map(Query)
+            .map_err(error_400("Unable to deserialize query_params"))
+    }
+}
+
+/// The parameters of the matched route, see `Request::params_as`
+pub struct Path<P>(pub P);
+
+impl<T: Clone + Send + Sync, P: DeserializeOwned> FromRequest<T> for Path<P> {
+    fn from_request(req: &Request<T>) -> Result<Self, HttpError> {
+        req.params_as()
+            .map(Path)
+            .map_err(error_400("Unable to deserialize path_params"))
+    }
+}
+
+/// Names the header read by `Header<N>`, lowercase like the keys of `Request::headers`
+pub trait HeaderName {
+    const NAME: &'static str;
+}
+
+/// The value of the `N::NAME` header, which must be present
+pub struct Header<N: HeaderName> {
+    pub value: String,
+    name: PhantomData<N>,
+}
+
+impl<T: Clone + Send + Sync, N: HeaderName> FromRequest<T> for Header<N> {
+    fn from_request(req: &Request<T>) -> Result<Self, HttpError> {
+        match req.headers.get(N::NAME) {
+            Some(value) => Ok(Header {
+                value: value.clone(),
+                name: PhantomData,
+            }),
+            None => Err(error_400("Missing header")(N::NAME)),
+        }
+    }
+}
+
+/// A clone of the state the `App` was built with
+pub struct State<T>(pub T);
+
+impl<T: Clone + Send + Sync> FromRequest<T> for State<T> {
+    fn from_request(req: &Request<T>) -> Result<Self, HttpError> {
+        Ok(State(req.context.clone()))
+    }
+}
@@ -0,0 +1,258 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+
+use futures::future::FutureExt;
+
+use crate::extract::FromRequest;
+use crate::request::Request;
+use crate::response::{IntoResponse, Response};
+use crate::HttpError;
+
+pub trait Handler<T: Clone + Send + Sync>: objekt::Clone + Sync + Send {
+    fn invoke(&self, req: Request<T>) -> Result<Response, HttpError>;
+}
+objekt::clone_trait_object!(<T: Clone + Send + Sync> Handler<T>);
+
+impl<T, F, R> Handler<T> for F
+where
+    T: Clone + Send + Sync,
+    F: Fn(Request<T>) -> R + Clone + Send + Sync,
+    R: IntoResponse,
+{
+    fn invoke(&self, req: Request<T>) -> Result<Response, HttpError> {
+        Ok(self(req).into_response())
+    }
+}
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Response, HttpError>> + Send>>;
+
+/// Like `Handler`, but the response is awaited on the server runtime,
+/// so the handler can wait for I/O without blocking a worker
+pub trait AsyncHandler<T: Clone + Send + Sync>: objekt::Clone + Sync + Send {
+    fn invoke(&self, req: Request<T>) -> HandlerFuture;
+}
+objekt::clone_trait_object!(<T: Clone + Send + Sync> AsyncHandler<T>);
+
+impl<T, F, Fut> AsyncHandler<T> for F
+where
+    T: Clone + Send + Sync,
+    F: Fn(Request<T>) -> Fut + Clone + Send + Sync,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    fn invoke(&self, req: Request<T>) -> HandlerFuture {
+        Box::pin(self(req).map(|output| Ok(output.into_response())))
+    }
+}
+
+#[derive(Clone)]
+enum Kind<T: Clone + Send + Sync> {
+    Sync(Box<dyn Handler<T>>),
+    Async(Box<dyn AsyncHandler<T>>),
+}
+
+/// A handler registered in an `App`, whatever its flavour
+#[derive(Clone)]
+pub struct Endpoint<T: Clone + Send + Sync> {
+    kind: Kind<T>,
+    name: &'static str,
+    streams_body: bool,
+}
+
+impl<T: Clone + Send + Sync> Endpoint<T> {
+    fn new<H: ?Sized>(kind: Kind<T>) -> Self {
+        Endpoint {
+            kind,
+            name: std::any::type_name::<H>(),
+            streams_body: false,
+        }
+    }
+
+    /// The type name of the handler, a closure path like `app::main::{{closure}}` for closures
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Whether the handler reads `Request::stream` instead of `Request::body`
+    pub fn streams_body(&self) -> bool {
+        self.streams_body
+    }
+
+    pub(crate) async fn call(&self, req: Request<T>) -> Result<Response, HttpError> {
+        match &self.kind {
+            Kind::Sync(handler) => handler.invoke(req),
+            Kind::Async(handler) => handler.invoke(req).await,
+        }
+    }
+}
+
+/// Anything `App` accepts as a handler. `Marker` only tells the implementations apart
+pub trait IntoEndpoint<T: Clone + Send + Sync, Marker> {
+    fn into_endpoint(self) -> Endpoint<T>;
+}
+
+pub mod markers {
+    use std::marker::PhantomData;
+
+    pub struct Sync;
+    pub struct DynSync;
+    pub struct Async;
+    pub struct DynAsync;
+    pub struct Closure;
+    pub struct AsyncClosure;
+    pub struct Extract<A>(PhantomData<A>);
+    pub struct AsyncExtract<A>(PhantomData<A>);
+    pub struct Streamed<M>(PhantomData<M>);
+}
+
+/// Wraps a handler that reads the request body from `Request::stream` as it arrives,
+/// for uploads too large to be buffered: `Request::body` stays empty
+pub struct Streamed<H>(pub H);
+
+impl<T: Clone + Send + Sync, M, H: IntoEndpoint<T, M>> IntoEndpoint<T, markers::Streamed<M>>
+    for Streamed<H>
+{
+    fn into_endpoint(self) -> Endpoint<T> {
+        Endpoint {
+            streams_body: true,
+            ..self.0.into_endpoint()
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync, H: Handler<T> + 'static> IntoEndpoint<T, markers::Sync> for Box<H> {
+    fn into_endpoint(self) -> Endpoint<T> {
+        Endpoint::new::<H>(Kind::Sync(self))
+    }
+}
+
+impl<T: Clone + Send + Sync> IntoEndpoint<T, markers::DynSync> for Box<dyn Handler<T>> {
+    fn into_endpoint(self) -> Endpoint<T> {
+        Endpoint::new::<dyn Handler<T>>(Kind::Sync(self))
+    }
+}
+
+impl<T: Clone + Send + Sync, H: AsyncHandler<T> + 'static> IntoEndpoint<T, markers::Async>
+    for Box<H>
+{
+    fn into_endpoint(self) -> Endpoint<T> {
+        Endpoint::new::<H>(Kind::Async(self))
+    }
+}
+
+impl<T: Clone + Send + Sync> IntoEndpoint<T, markers::DynAsync> for Box<dyn AsyncHandler<T>> {
+    fn into_endpoint(self) -> Endpoint<T> {
+        Endpoint::new::<dyn AsyncHandler<T>>(Kind::Async(self))
+    }
+}
+
+impl<T, F, R> IntoEndpoint<T, markers::Closure> for F
+where
+    T: Clone + Send + Sync,
+    F: Fn(Request<T>) -> R + Clone + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn into_endpoint(self) -> Endpoint<T> {
+        Endpoint::new::<F>(Kind::Sync(Box::new(self)))
+    }
+}
+
+impl<T, F, Fut> IntoEndpoint<T, markers::AsyncClosure> for F
+where
+    T: Clone + Send + Sync,
+    F: Fn(Request<T>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    fn into_endpoint(self) -> Endpoint<T> {
+        Endpoint::new::<F>(Kind::Async(Box::new(self)))
+    }
+}
+
+// Calls `handler` with its arguments built through `FromRequest`
+struct Extractor<F, A> {
+    handler: F,
+    args: PhantomData<fn() -> A>,
+}
+
+impl<F: Clone, A> Clone for Extractor<F, A> {
+    fn clone(&self) -> Self {
+        Extractor {
+            handler: self.handler.clone(),
+            args: PhantomData,
+        }
+    }
+}
+
+macro_rules! extractor_handler {
+    ($($arg:ident),+) => {
+        impl<T, F, R, $($arg),+> Handler<T> for Extractor<F, ($($arg,)+)>
+        where
+            T: Clone + Send + Sync,
+            F: Fn($($arg),+) -> R + Clone + Send + Sync,
+            R: IntoResponse,
+            $($arg: FromRequest<T>),+
+        {
+            #[allow(non_snake_case)]
+            fn invoke(&self, req: Request<T>) -> Result<Response, HttpError> {
+                $(let $arg = $arg::from_request(&req)?;)+
+                Ok((self.handler)($($arg),+).into_response())
+            }
+        }
+
+        impl<T, F, Fut, $($arg),+> AsyncHandler<T> for Extractor<F, ($($arg,)+)>
+        where
+            T: Clone + Send + Sync,
+            F: Fn($($arg),+) -> Fut + Clone + Send + Sync,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoResponse,
+            $($arg: FromRequest<T>),+
+        {
+            #[allow(non_snake_case)]
+            fn invoke(&self, req: Request<T>) -> HandlerFuture {
+                $(let $arg = match $arg::from_request(&req) {
+                    Ok(value) => value,
+                    Err(error) => return Box::pin(futures::future::ready(Err(error))),
+                };)+
+                Box::pin((self.handler)($($arg),+).map(|output| Ok(output.into_response())))
+            }
+        }
+
+        impl<T, F, R, $($arg),+> IntoEndpoint<T, markers::Extract<($($arg,)+)>> for F
+        where
+            T: Clone + Send + Sync,
+            F: Fn($($arg),+) -> R + Clone + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest<T> + 'static),+
+        {
+            fn into_endpoint(self) -> Endpoint<T> {
+                Endpoint::new::<F>(Kind::Sync(Box::new(Extractor {
+                    handler: self,
+                    args: PhantomData::<fn() -> ($($arg,)+)>,
+                })))
+            }
+        }
+
+        impl<T, F, Fut, $($arg),+> IntoEndpoint<T, markers::AsyncExtract<($($arg,)+)>> for F
+        where
+            T: Clone + Send + Sync,
+            F: Fn($($arg),+) -> Fut + Clone + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoResponse,
+            $($arg: FromRequest<T> + 'static),+
+        {
+            fn into_endpoint(self) -> Endpoint<T> {
+                Endpoint::new::<F>(Kind::Async(Box::new(Extractor {
+                    handler: self,
+                    args: PhantomData::<fn() -> ($($arg,)+)>,
+                })))
+            }
+        }
+    };
+}
+
+extractor_handler!(A1);
+extractor_handler!(A1, A2);
+extractor_handler!(A1, A2, A3);
+extractor_handler!(A1, A2, A3, A4);
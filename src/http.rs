@@ -1,36 +1,156 @@
 use bytes::BytesMut;
 use httparse::Status::{Complete, Partial};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io;
+use std::sync::Arc;
 use tokio::codec::{Decoder, Encoder};
 
 use crate::request::Request;
 use crate::response::Response;
+use crate::HttpError;
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 static REQUEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+// Longest chunk-size line, extensions included, and longest trailer section
+const MAX_CHUNK_LINE: usize = 4096;
+const MAX_TRAILERS: usize = 8192;
+// Most room made at once for a body still on its way, whatever length it announces
+const MAX_BODY_RESERVE: usize = 64 * 1024;
+
+/// Sizes past which a request is refused: with a 413, 414 or 431 response,
+/// then the connection is closed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_headers: usize,
+    /// Bytes in the request line and the headers, up to the empty line ending them
+    pub max_header_size: usize,
+    /// Bytes in a buffered body: the bodies streamed to a `Streamed` handler are not limited
+    pub max_body_size: usize,
+    pub max_uri_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_headers: 100,
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+            max_uri_length: 8 * 1024,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct HttpCodec<T: Clone + Sync + Send> {
     pub with_headers: bool,
     pub with_query_string: bool,
     pub logger: slog::Logger,
     pub context: T,
+    pub limits: Limits,
+    // One entry per decoded request still waiting for its response: was it a HEAD?
+    head_requests: VecDeque<bool>,
+    // Between the head of a streamed response and its end, unless the body is not sent
+    streaming: bool,
+    // Which requests get their body as `Message::Chunk`s instead of a buffered one
+    streams_body: Option<StreamsBody<T>>,
+    // What is left of the request body being given as `Message::Chunk`s
+    streamed_body: Option<StreamedBody>,
+    // The complete chunks of the buffered chunked body still arriving after the head,
+    // so they are decoded once
+    chunked_body: Option<ChunkedBody>,
+}
+
+type StreamsBody<T> = Arc<dyn Fn(&Request<T>) -> bool + Send + Sync>;
+
+#[derive(Clone, Copy)]
+enum StreamedBody {
+    // Bytes left
+    Length(usize),
+    // Bytes left in the current chunk before its CRLF, `None` before a chunk-size line
+    Chunked(Option<usize>),
+}
+
+/// What `HttpCodec` reads: a whole request, or the head of one whose body
+/// follows as chunks up to its end
+pub enum Message<T: Clone + Sync + Send> {
+    Request(Request<T>),
+    Head(Request<T>),
+    Chunk(Vec<u8>),
+    End,
+}
+
+/// What `HttpCodec` writes: a whole response, or the head of a streamed one
+/// followed by its chunks and its end
+pub enum Frame {
+    Response(Response),
+    Head(Response),
+    Chunk(Vec<u8>),
+    End,
+}
+
+impl From<Response> for Frame {
+    fn from(response: Response) -> Frame {
+        Frame::Response(response)
+    }
+}
+
+impl<T: Clone + Sync + Send> HttpCodec<T> {
+    pub fn new(logger: slog::Logger, context: T) -> Self {
+        HttpCodec {
+            with_headers: true,
+            with_query_string: true,
+            logger,
+            context,
+            limits: Limits::default(),
+            head_requests: VecDeque::new(),
+            streaming: false,
+            streams_body: None,
+            streamed_body: None,
+            chunked_body: None,
+        }
+    }
+
+    /// Gives the body of the requests matching `streams_body` as it arrives:
+    /// their `Message::Head` is followed by `Message::Chunk`s and a `Message::End`
+    pub fn set_streams_body<F>(&mut self, streams_body: F)
+    where
+        F: Fn(&Request<T>) -> bool + Send + Sync + 'static,
+    {
+        self.streams_body = Some(Arc::new(streams_body));
+    }
+
+    /// Whether the chunks of the last `Frame::Head` are expected: false when
+    /// the body is not sent, as for HEAD requests or 204 and 304 responses
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
 }
 
 impl<T: Clone + Send + Sync> Decoder for HttpCodec<T> {
-    type Item = Request<T>;
+    type Item = Message<T>;
     type Error = io::Error;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request<T>>> {
-        let mut headers = [httparse::EMPTY_HEADER; 16];
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Message<T>>> {
+        if let Some(streamed_body) = self.streamed_body {
+            return self.decode_body(streamed_body, buf);
+        }
+
+        let limits = self.limits;
+        let mut headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
         let mut req = httparse::Request::new(&mut headers);
 
         let headers = req.parse(buf);
 
+        if let Err(httparse::Error::TooManyHeaders) = headers {
+            return Err(rejected(431, "Too many headers"));
+        }
         if headers.is_err() {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to parse HTTP headers"));
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Unable to parse HTTP headers",
+            ));
         }
         let headers = headers.unwrap();
 
@@ -39,12 +159,25 @@ impl<T: Clone + Send + Sync> Decoder for HttpCodec<T> {
             Partial => 0,
         };
         if header_lenght == 0 {
+            if uri_length(buf) > limits.max_uri_length {
+                return Err(rejected(414, "URI too long"));
+            }
+            if buf.len() > limits.max_header_size {
+                return Err(rejected(431, "Header section too large"));
+            }
             return Ok(None);
         }
 
         let method = req.method.unwrap();
         let url = req.path.unwrap();
 
+        if url.len() > limits.max_uri_length {
+            return Err(rejected(414, "URI too long"));
+        }
+        if header_lenght > limits.max_header_size {
+            return Err(rejected(431, "Header section too large"));
+        }
+
         let index = url.find('?').or_else(|| Some(url.len())).unwrap();
 
         let path = &url[..index];
@@ -58,9 +191,11 @@ impl<T: Clone + Send + Sync> Decoder for HttpCodec<T> {
 
         let content_type_header_name = "content-type";
         let content_length_header_name = "content-length";
+        let transfer_encoding_header_name = "transfer-encoding";
 
-        let mut content_length = 0;
+        let mut content_length = None;
         let mut content_type = None;
+        let mut transfer_encoding = None;
         let mut c: u8 = 0;
         let mut headers: HashMap<String, String> = HashMap::new();
         for header in req.headers.iter() {
@@ -68,7 +203,8 @@ impl<T: Clone + Send + Sync> Decoder for HttpCodec<T> {
 
             let with_value = with_headers
                 || header_name == content_type_header_name
-                || header_name == content_length_header_name;
+                || header_name == content_length_header_name
+                || header_name == transfer_encoding_header_name;
             let header_value = if with_value {
                 Some(String::from_utf8_lossy(header.value).to_string())
             } else {
@@ -78,68 +214,531 @@ impl<T: Clone + Send + Sync> Decoder for HttpCodec<T> {
                 headers.insert(header_name.clone(), header_value.clone().unwrap().clone());
             }
 
+            // `c` counts each of them once, so a repeated header is still checked
             if header_name == content_type_header_name {
+                if content_type.is_none() {
+                    c += 1;
+                }
                 content_type = Some(header_value.unwrap().clone());
-                c += 1;
             } else if header_name == content_length_header_name {
-                content_length = header_value.unwrap().parse::<usize>().unwrap();
-                c += 1;
+                let value = header_value.unwrap().parse::<usize>();
+                let value = value.map_err(|_| rejected(400, "Invalid Content-Length"))?;
+                match content_length {
+                    None => c += 1,
+                    Some(previous) if previous != value => {
+                        return Err(rejected(400, "Conflicting Content-Length headers"))
+                    }
+                    Some(_) => {}
+                }
+                content_length = Some(value);
+            } else if header_name == transfer_encoding_header_name {
+                if transfer_encoding.is_none() {
+                    c += 1;
+                }
+                transfer_encoding = header_value;
             }
 
-            if !with_headers && c == 2 {
+            if !with_headers && c == 3 {
                 break;
             }
         }
 
-        let request = Request {
+        let chunked = match transfer_encoding {
+            None => false,
+            Some(_) if content_length.is_some() => {
+                return Err(rejected(
+                    400,
+                    "Both Transfer-Encoding and Content-Length are set",
+                ))
+            }
+            Some(ref coding) if coding.trim().eq_ignore_ascii_case("chunked") => true,
+            Some(_) => return Err(rejected(501, "Unsupported Transfer-Encoding")),
+        };
+
+        let mut request = Request {
             method: method.to_owned(),
             path: path.to_owned(),
             query_string: query_string.to_owned(),
             headers,
+            trailers: HashMap::new(),
+            params: HashMap::new(),
+            tail: None,
             content_type,
-            content_length,
+            // unknown until the end of a streamed chunked body
+            content_length: content_length.unwrap_or(0),
             header_lenght,
-            body: buf.split_to(header_lenght + content_length)[header_lenght..].to_vec(),
-            logger: slog::Logger::new(
-                &self.logger,
-                o!(
-                    "reqId" => REQUEST_COUNTER.fetch_add(1, Ordering::SeqCst)
-                ),
-            ),
+            body: vec![],
+            stream: None,
+            logger: self.logger.clone(),
             context: self.context.clone(),
         };
 
-        Ok(Some(request))
+        let streams_body = self
+            .streams_body
+            .as_ref()
+            .filter(|streams_body| streams_body(&request))
+            .is_some();
+        if streams_body {
+            buf.split_to(header_lenght);
+            self.streamed_body = Some(if chunked {
+                StreamedBody::Chunked(None)
+            } else {
+                StreamedBody::Length(request.content_length)
+            });
+        } else if chunked {
+            let mut chunked = self.chunked_body.take().unwrap_or_default();
+            if !decode_chunked(&buf[header_lenght..], limits.max_body_size, &mut chunked)? {
+                self.chunked_body = Some(chunked);
+                return Ok(None);
+            }
+            if with_headers {
+                for (name, value) in chunked.trailers {
+                    request.trailers.entry(name).or_insert(value);
+                }
+            }
+            buf.split_to(header_lenght + chunked.length);
+            request.content_length = chunked.body.len();
+            request.body = chunked.body;
+        } else {
+            if request.content_length > limits.max_body_size {
+                return Err(rejected(413, "Body too large"));
+            }
+            let length = header_lenght
+                .checked_add(request.content_length)
+                .ok_or_else(|| rejected(400, "Invalid Content-Length"))?;
+            if buf.len() < length {
+                // Waits for the rest of the body, making room for it
+                buf.reserve(std::cmp::min(length - buf.len(), MAX_BODY_RESERVE));
+                return Ok(None);
+            }
+            request.body = buf.split_to(length)[header_lenght..].to_vec();
+        }
+
+        request.logger = slog::Logger::new(
+            &self.logger,
+            o!(
+                "reqId" => REQUEST_COUNTER.fetch_add(1, Ordering::SeqCst)
+            ),
+        );
+        self.head_requests.push_back(request.method == "HEAD");
+
+        Ok(Some(if streams_body {
+            Message::Head(request)
+        } else {
+            Message::Request(request)
+        }))
+    }
+}
+
+impl<T: Clone + Send + Sync> HttpCodec<T> {
+    fn decode_body(
+        &mut self,
+        streamed_body: StreamedBody,
+        buf: &mut BytesMut,
+    ) -> io::Result<Option<Message<T>>> {
+        self.streamed_body = Some(streamed_body);
+        match streamed_body {
+            StreamedBody::Length(0) => {
+                self.streamed_body = None;
+                Ok(Some(Message::End))
+            }
+            StreamedBody::Length(left) => {
+                if buf.is_empty() {
+                    return Ok(None);
+                }
+                let length = left.min(buf.len());
+                self.streamed_body = Some(StreamedBody::Length(left - length));
+                Ok(Some(Message::Chunk(buf.split_to(length).to_vec())))
+            }
+            StreamedBody::Chunked(None) => {
+                let (line_length, size) = match read_line(buf, MAX_CHUNK_LINE, "Chunk size line")? {
+                    Some(line) => (line.len() + 2, chunk_size(line)?),
+                    None => return Ok(None),
+                };
+                if size > 0 {
+                    buf.split_to(line_length);
+                    return self.decode_body(StreamedBody::Chunked(Some(size)), buf);
+                }
+                // The head is gone already: the trailers are dropped
+                match decode_trailers(&buf[line_length..])? {
+                    Some((_, length)) => {
+                        buf.split_to(line_length + length);
+                        self.streamed_body = None;
+                        Ok(Some(Message::End))
+                    }
+                    None => Ok(None),
+                }
+            }
+            StreamedBody::Chunked(Some(0)) => {
+                if buf.len() < 2 {
+                    return Ok(None);
+                }
+                if &buf[..2] != b"\r\n" {
+                    return Err(rejected(400, "Missing CRLF after chunk data"));
+                }
+                buf.split_to(2);
+                self.decode_body(StreamedBody::Chunked(None), buf)
+            }
+            StreamedBody::Chunked(Some(left)) => {
+                if buf.is_empty() {
+                    return Ok(None);
+                }
+                let length = left.min(buf.len());
+                self.streamed_body = Some(StreamedBody::Chunked(Some(left - length)));
+                Ok(Some(Message::Chunk(buf.split_to(length).to_vec())))
+            }
+        }
+    }
+}
+
+/// A request the decoder refuses: the connection answers it with `status_code`, then closes
+fn rejected(status_code: u16, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        HttpError {
+            status_code,
+            error_message: message.to_owned(),
+            details: "".to_owned(),
+        },
+    )
+}
+
+/// The length of the request target in the request line at the start of `buf`,
+/// as far as it has arrived
+fn uri_length(buf: &[u8]) -> usize {
+    let line_end = buf.iter().position(|&b| b == b'\n').unwrap_or(buf.len());
+    let mut parts = buf[..line_end].splitn(3, |&b| b == b' ').skip(1);
+    parts.next().map_or(0, |uri| uri.len())
+}
+
+/// The line at the start of `buf`, without its CRLF, or `None` until it is complete
+fn read_line<'a>(buf: &'a [u8], max_length: usize, what: &str) -> io::Result<Option<&'a [u8]>> {
+    match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) if end <= max_length => Ok(Some(&buf[..end])),
+        None if buf.len() <= max_length + 1 => Ok(None),
+        _ => Err(rejected(400, &format!("{} too long", what))),
+    }
+}
+
+// (lowercase name, value)
+type Trailers = Vec<(String, String)>;
+
+#[derive(Clone, Default)]
+struct ChunkedBody {
+    body: Vec<u8>,
+    trailers: Trailers,
+    // bytes taken in the buffer by the complete chunks, and the trailers at the end
+    length: usize,
+}
+
+/// Carries on decoding the chunked body at the start of `buf` from the last complete
+/// chunk in `chunked`, chunk extensions being ignored. Gives `false` until the body is complete.
+/// The limit counts the chunk-size lines too, the body being buffered with them
+fn decode_chunked(buf: &[u8], max_body_size: usize, chunked: &mut ChunkedBody) -> io::Result<bool> {
+    loop {
+        let mut position = chunked.length;
+        let line = match read_line(&buf[position..], MAX_CHUNK_LINE, "Chunk size line")? {
+            Some(line) => line,
+            None => return Ok(false),
+        };
+        position += line.len() + 2;
+
+        let size = chunk_size(line)?;
+        if size == 0 {
+            return match decode_trailers(&buf[position..])? {
+                Some((trailers, length)) => {
+                    chunked.trailers = trailers;
+                    chunked.length = position + length;
+                    Ok(true)
+                }
+                None => Ok(false),
+            };
+        }
+
+        let end = position
+            .checked_add(size)
+            .ok_or_else(|| rejected(400, "Invalid chunk size"))?;
+        if end.saturating_add(2) > max_body_size {
+            return Err(rejected(413, "Body too large"));
+        }
+        if buf.len() < end + 2 {
+            return Ok(false);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(rejected(400, "Missing CRLF after chunk data"));
+        }
+        chunked.body.extend_from_slice(&buf[position..end]);
+        chunked.length = end + 2;
+    }
+}
+
+/// The size in a chunk-size line, without its extensions
+fn chunk_size(line: &[u8]) -> io::Result<usize> {
+    let line = String::from_utf8_lossy(line);
+    let size = line
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim_matches(|c| c == ' ' || c == '\t');
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(rejected(400, "Invalid chunk size"));
+    }
+    usize::from_str_radix(size, 16).map_err(|_| rejected(400, "Invalid chunk size"))
+}
+
+/// The trailer fields at the start of `buf` and the bytes they take up to the
+/// empty line ending them. Gives `None` until that line is there
+fn decode_trailers(buf: &[u8]) -> io::Result<Option<(Trailers, usize)>> {
+    let mut position = 0;
+    let mut trailers = vec![];
+    loop {
+        let max_length = MAX_TRAILERS.saturating_sub(position);
+        let line = match read_line(&buf[position..], max_length, "Trailer section")? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        position += line.len() + 2;
+        if line.is_empty() {
+            return Ok(Some((trailers, position)));
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let value = match parts.next() {
+            Some(value) if is_token(name) => value.trim(),
+            _ => return Err(rejected(400, "Invalid trailer field")),
+        };
+        trailers.push((name.to_lowercase(), value.to_owned()));
     }
 }
 
+fn invalid_response(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// token = 1*tchar
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// *( HTAB / SP / VCHAR / obs-text ): used for both reason phrases and header values
+fn is_field_value(s: &str) -> bool {
+    s.bytes()
+        .all(|b| b == b'\t' || b == b' ' || (b >= 0x21 && b != 0x7f))
+}
+
+fn status_line(response: &Response) -> io::Result<String> {
+    let status_code = http::StatusCode::from_u16(response.status_code)
+        .map_err(|_| invalid_response(format!("Invalid status code {}", response.status_code)))?;
+
+    let reason_phrase = match &response.reason_phrase {
+        Some(reason_phrase) => {
+            if !is_field_value(reason_phrase) {
+                return Err(invalid_response(format!(
+                    "Invalid reason phrase {:?}",
+                    reason_phrase
+                )));
+            }
+            reason_phrase.as_str()
+        }
+        None => status_code.canonical_reason().unwrap_or(""),
+    };
+
+    Ok(format!(
+        "HTTP/1.1 {} {}\r\n",
+        status_code.as_u16(),
+        reason_phrase
+    ))
+}
+
+// 1xx, 204 and 304 responses never carry a body nor its length
+fn is_bodyless_status(status_code: u16) -> bool {
+    status_code < 200 || status_code == 204 || status_code == 304
+}
+
+// `body_length` is None for a streamed body, sent with `Transfer-Encoding: chunked`
+fn header_lines(
+    response: &Response,
+    body_length: Option<usize>,
+    is_head_request: bool,
+) -> io::Result<String> {
+    let mut names: Vec<&String> = response.headers.keys().collect();
+    names.sort();
+
+    // The length a HEAD handler advertises for the body it doesn't send
+    let mut head_length = None;
+    let mut with_connection = false;
+    let mut output = String::new();
+    for name in names {
+        if !is_token(name) {
+            return Err(invalid_response(format!("Invalid header name {:?}", name)));
+        }
+        let lowercase_name = name.to_ascii_lowercase();
+        for value in &response.headers[name] {
+            if !is_field_value(value) {
+                return Err(invalid_response(format!(
+                    "Invalid value for header {}: {:?}",
+                    name, value
+                )));
+            }
+
+            match lowercase_name.as_ref() {
+                // Content-Length is always computed from the body, but for HEAD requests
+                "content-length" => {
+                    let body_length = match body_length {
+                        Some(body_length) => body_length,
+                        None => {
+                            return Err(invalid_response(
+                                "Content-Length is set on a streamed response".to_owned(),
+                            ))
+                        }
+                    };
+                    let length = value.trim().parse::<usize>().ok();
+                    if is_head_request
+                        && length.is_some()
+                        && (head_length.is_none() || head_length == length)
+                    {
+                        head_length = length;
+                        continue;
+                    }
+                    if length != Some(body_length) {
+                        return Err(invalid_response(format!(
+                            "Content-Length {:?} does not match the body length {}",
+                            value, body_length
+                        )));
+                    }
+                    continue;
+                }
+                "transfer-encoding" => {
+                    return Err(invalid_response(
+                        "Transfer-Encoding is computed from the body".to_owned(),
+                    ));
+                }
+                "content-type" if response.content_type.is_some() => {
+                    return Err(invalid_response(
+                        "Content-Type is set both as header and as content_type".to_owned(),
+                    ));
+                }
+                "connection" => with_connection = true,
+                _ => {}
+            }
+
+            output += name;
+            output += ": ";
+            output += value;
+            output += "\r\n";
+        }
+    }
+
+    if !with_connection {
+        output += "Connection: keep-alive\r\n";
+    }
+    if !is_bodyless_status(response.status_code) {
+        match body_length {
+            Some(body_length) => {
+                // A HEAD handler with neither a body nor a Content-Length doesn't tell the length
+                let length = if is_head_request {
+                    head_length.or_else(|| Some(body_length).filter(|length| *length > 0))
+                } else {
+                    Some(body_length)
+                };
+                if let Some(length) = length {
+                    output += "Content-Length: ";
+                    output += &length.to_string();
+                    output += "\r\n";
+                }
+            }
+            None => output += "Transfer-Encoding: chunked\r\n",
+        }
+    }
+    if let Some(content_type) = &response.content_type {
+        if !is_field_value(content_type) {
+            return Err(invalid_response(format!(
+                "Invalid content type {:?}",
+                content_type
+            )));
+        }
+        output += "Content-Type: ";
+        output += content_type;
+        output += "\r\n";
+    }
+
+    Ok(output)
+}
+
+/// Runs the checks `HttpCodec` makes while encoding `response`, so that an invalid
+/// response is replaced before anything is written
+pub(crate) fn check_response(response: &Response, is_head_request: bool) -> io::Result<()> {
+    if response.stream.is_some() && !response.body.is_empty() {
+        return Err(invalid_response(
+            "A streamed response has a body".to_owned(),
+        ));
+    }
+    let body_length = match response.stream {
+        Some(_) => None,
+        None => Some(response.body.len()),
+    };
+    status_line(response)?;
+    header_lines(response, body_length, is_head_request)?;
+    Ok(())
+}
+
 impl<T: Clone + Send + Sync> Encoder for HttpCodec<T> {
-    type Item = Response;
+    type Item = Frame;
     type Error = io::Error;
 
-    fn encode(&mut self, mut response: Response, buf: &mut BytesMut) -> io::Result<()> {
-        response.body.push(b'\n');
-        let body = response.body;
-        let len = body.len().to_string();
-        let content_type = match response.content_type {
-            Some(ct) => "\r\nContent-type: ".to_owned() + &ct,
-            None => "".to_owned(),
-        };
-        let output = "HTTP/1.1 ".to_owned()
-            // + &response.status_code.to_string()[..]
-            + "200"
-            + " OK"
-            + "\r\n"
-            + "Connection: keep-alive"
-            + "\r\n"
-            + "Content-length:"
-            + &len[..]
-            + &content_type
-            + "\r\n"
-            + "\r\n";
-
-        buf.extend_from_slice(output.as_bytes());
-        buf.extend_from_slice(&body[..]);
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        match frame {
+            Frame::Response(response) => {
+                if response.stream.is_some() {
+                    return Err(invalid_response(
+                        "A streamed response is sent as Frame::Head".to_owned(),
+                    ));
+                }
+                let is_head_request = self.head_requests.pop_front().unwrap_or(false);
+
+                let output = status_line(&response)?
+                    + &header_lines(&response, Some(response.body.len()), is_head_request)?
+                    + "\r\n";
+
+                buf.extend_from_slice(output.as_bytes());
+                // HEAD responses advertise the Content-Length of the body they don't send
+                if !is_head_request && !is_bodyless_status(response.status_code) {
+                    buf.extend_from_slice(&response.body[..]);
+                }
+            }
+            Frame::Head(response) => {
+                if !response.body.is_empty() {
+                    return Err(invalid_response(
+                        "A streamed response has a body".to_owned(),
+                    ));
+                }
+                let is_head_request = self.head_requests.pop_front().unwrap_or(false);
+
+                let output = status_line(&response)?
+                    + &header_lines(&response, None, is_head_request)?
+                    + "\r\n";
+
+                buf.extend_from_slice(output.as_bytes());
+                self.streaming = !is_head_request && !is_bodyless_status(response.status_code);
+            }
+            // An empty chunk would end the body
+            Frame::Chunk(ref chunk) if !self.streaming || chunk.is_empty() => {}
+            Frame::Chunk(chunk) => {
+                buf.extend_from_slice(format!("{:X}\r\n", chunk.len()).as_bytes());
+                buf.extend_from_slice(&chunk[..]);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Frame::End => {
+                if self.streaming {
+                    buf.extend_from_slice(b"0\r\n\r\n");
+                }
+                self.streaming = false;
+            }
+        }
         Ok(())
     }
 }
@@ -147,6 +746,7 @@ impl<T: Clone + Send + Sync> Encoder for HttpCodec<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::response::IntoResponse;
 
     use sloggers::terminal::{Destination, TerminalLoggerBuilder};
     use sloggers::types::Severity;
@@ -159,6 +759,16 @@ mod tests {
         builder.build().unwrap()
     }
 
+    fn decode_request(
+        http: &mut HttpCodec<i32>,
+        input: &mut BytesMut,
+    ) -> io::Result<Option<Request<i32>>> {
+        Ok(http.decode(input)?.map(|message| match message {
+            Message::Request(request) => request,
+            _ => panic!("Not a whole request"),
+        }))
+    }
+
     #[test]
     fn http_decode_get() {
         let mut input = BytesMut::new();
@@ -167,10 +777,9 @@ mod tests {
         let mut http = HttpCodec {
             with_headers: false,
             with_query_string: false,
-            logger: get_logger(),
-            context: 0,
+            ..HttpCodec::new(get_logger(), 0)
         };
-        let request = http.decode(&mut input);
+        let request = decode_request(&mut http, &mut input);
 
         assert!(request.is_ok());
         let request = request.unwrap().unwrap();
@@ -195,10 +804,9 @@ mod tests {
         let mut http = HttpCodec {
             with_headers: false,
             with_query_string: false,
-            logger: get_logger(),
-            context: 0,
+            ..HttpCodec::new(get_logger(), 0)
         };
-        let request = http.decode(&mut input);
+        let request = decode_request(&mut http, &mut input);
 
         assert!(request.is_ok());
         let request = request.unwrap().unwrap();
@@ -221,12 +829,10 @@ mod tests {
         input.extend_from_slice(b"GET / HTTP/1.1\r\nHost: localhost:8880\r\nUser-Agent: curl/7.54.0\r\nAccept: */*\r\n\r\n");
 
         let mut http = HttpCodec {
-            with_headers: true,
             with_query_string: false,
-            logger: get_logger(),
-            context: 0,
+            ..HttpCodec::new(get_logger(), 0)
         };
-        let request = http.decode(&mut input);
+        let request = decode_request(&mut http, &mut input);
 
         assert!(request.is_ok());
         let request = request.unwrap().unwrap();
@@ -260,11 +866,9 @@ mod tests {
 
         let mut http = HttpCodec {
             with_headers: false,
-            with_query_string: true,
-            logger: get_logger(),
-            context: 0,
+            ..HttpCodec::new(get_logger(), 0)
         };
-        let request = http.decode(&mut input);
+        let request = decode_request(&mut http, &mut input);
 
         assert!(request.is_ok());
         let request = request.unwrap().unwrap();
@@ -281,4 +885,704 @@ mod tests {
         let empty_vec: Vec<u8> = Vec::new();
         assert_eq!(input.to_vec(), empty_vec);
     }
+
+    #[test]
+    fn http_decode_partial_body() {
+        let mut input = BytesMut::new();
+        input.extend_from_slice(b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n{\"message\"");
+        let buffered = input.len();
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        assert!(decode_request(&mut http, &mut input).unwrap().is_none());
+        assert_eq!(input.len(), buffered);
+        assert!(input.capacity() >= buffered - 10 + 1000);
+
+        input.extend_from_slice(&[b' '; 990]);
+        let request = decode_request(&mut http, &mut input).unwrap().unwrap();
+        assert_eq!(request.body.len(), 1000);
+        assert!(request.body.starts_with(b"{\"message\""));
+        assert_eq!(input.to_vec(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn http_decode_byte_by_byte() {
+        let full = b"POST /a?b=c HTTP/1.1\r\nHost: localhost:8880\r\nContent-type: application/json\r\nContent-Length: 16\r\n\r\n{\"message\":\"aa\"}GET / HTTP/1.1\r\n\r\nPUT /d HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc";
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let mut input = BytesMut::from(&full[..]);
+        let mut expected = vec![];
+        while let Some(request) = decode_request(&mut http, &mut input).unwrap() {
+            expected.push(request);
+        }
+        assert_eq!(expected.len(), 3);
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let mut input = BytesMut::new();
+        let mut requests = vec![];
+        for byte in full.iter() {
+            input.extend_from_slice(&[*byte]);
+            while let Some(request) = decode_request(&mut http, &mut input).unwrap() {
+                requests.push(request);
+            }
+        }
+        assert_eq!(input.to_vec(), Vec::<u8>::new());
+
+        assert_eq!(requests.len(), expected.len());
+        for (request, expected) in requests.iter().zip(expected.iter()) {
+            assert_eq!(request.method, expected.method);
+            assert_eq!(request.path, expected.path);
+            assert_eq!(request.query_string, expected.query_string);
+            assert_eq!(request.headers, expected.headers);
+            assert_eq!(request.content_type, expected.content_type);
+            assert_eq!(request.content_length, expected.content_length);
+            assert_eq!(request.header_lenght, expected.header_lenght);
+            assert_eq!(request.body, expected.body);
+        }
+    }
+
+    #[test]
+    fn http_decode_chunked() {
+        let mut input = BytesMut::new();
+        input.extend_from_slice(b"POST /upload HTTP/1.1\r\nHost: localhost:8880\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nChecksum: abc\r\nHost: other\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let request = decode_request(&mut http, &mut input).unwrap().unwrap();
+
+        assert_eq!(request.path, "/upload");
+        assert_eq!(request.body, b"hello, world");
+        assert_eq!(request.content_length, 12);
+        assert_eq!(request.headers.get("checksum"), None);
+        assert_eq!(
+            request.headers.get("host"),
+            Some(&"localhost:8880".to_owned())
+        );
+        assert_eq!(request.trailers.get("checksum"), Some(&"abc".to_owned()));
+        assert_eq!(request.trailers.get("host"), Some(&"other".to_owned()));
+
+        let request = decode_request(&mut http, &mut input).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(input.to_vec(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn http_decode_chunked_partial() {
+        let full =
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nA\r\n0123456789\r\n0\r\n\r\n";
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let mut input = BytesMut::new();
+        for (index, byte) in full.iter().enumerate() {
+            input.extend_from_slice(&[*byte]);
+            let request = decode_request(&mut http, &mut input).unwrap();
+            if index + 1 < full.len() {
+                assert!(request.is_none());
+            } else {
+                assert_eq!(request.unwrap().body, b"0123456789");
+            }
+        }
+        assert_eq!(input.to_vec(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn http_decode_chunked_incremental() {
+        let mut input = BytesMut::from(
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, wo"[..],
+        );
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        assert!(decode_request(&mut http, &mut input).unwrap().is_none());
+        // the complete chunks are not decoded again
+        assert_eq!(http.chunked_body.as_ref().unwrap().body, b"hello");
+
+        input.extend_from_slice(b"rld\r\n0\r\n\r\n");
+        let request = decode_request(&mut http, &mut input).unwrap().unwrap();
+        assert_eq!(request.body, b"hello, world");
+        assert!(http.chunked_body.is_none());
+    }
+
+    fn rejected_status(input: &[u8]) -> Option<u16> {
+        rejected_status_with(Limits::default(), input)
+    }
+
+    fn rejected_status_with(limits: Limits, input: &[u8]) -> Option<u16> {
+        let mut http = HttpCodec {
+            limits,
+            ..HttpCodec::new(get_logger(), 0)
+        };
+        let error = http.decode(&mut BytesMut::from(input)).err()?;
+        let error = error.into_inner()?.downcast::<HttpError>().ok()?;
+        Some(error.status_code)
+    }
+
+    #[test]
+    fn http_decode_many_headers() {
+        let mut input = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        for index in 0..40 {
+            input.extend_from_slice(format!("X-Trace-{}: {}\r\n", index, index).as_bytes());
+        }
+        input.extend_from_slice(b"\r\n");
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let request = decode_request(&mut http, &mut input).unwrap().unwrap();
+        assert_eq!(request.headers.len(), 40);
+        assert_eq!(request.headers.get("x-trace-39"), Some(&"39".to_owned()));
+    }
+
+    #[test]
+    fn http_decode_limits() {
+        let limits = Limits {
+            max_headers: 2,
+            max_header_size: 64,
+            max_body_size: 8,
+            max_uri_length: 16,
+        };
+        let status = |input: &str| rejected_status_with(limits, input.as_bytes());
+
+        assert_eq!(status("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n"), None);
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            Some(431)
+        );
+        let long_header = format!("GET / HTTP/1.1\r\nA: {}\r\n", "a".repeat(64));
+        assert_eq!(status(&long_header), Some(431));
+        assert_eq!(status(&format!("{}\r\n", long_header)), Some(431));
+
+        assert_eq!(status("GET /123456789abcdef HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(status("GET /0123456789abcdef HTTP/1.1\r\n\r\n"), Some(414));
+        // refused as soon as the URI is too long, without waiting for the end of the line
+        assert_eq!(status("GET /0123456789abcdef"), Some(414));
+
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n"), None);
+        assert_eq!(
+            status("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"),
+            Some(413)
+        );
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        // the chunk-size lines count, as they are buffered with the body
+        assert_eq!(status(&format!("{}3\r\nabc\r\n", chunked)), None);
+        assert_eq!(status(&format!("{}3\r\nabc\r\n1\r\n", chunked)), Some(413));
+        assert_eq!(
+            status(&format!("{}1;{}\r\n", chunked, "x".repeat(8))),
+            Some(413)
+        );
+
+        let unlimited = Limits {
+            max_body_size: !0,
+            ..Limits::default()
+        };
+        let too_long = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", !0usize);
+        assert_eq!(
+            rejected_status_with(unlimited, too_long.as_bytes()),
+            Some(400)
+        );
+        // the room made for a body does not grow with the length the client announces
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let mut input = BytesMut::from(&b"POST / HTTP/1.1\r\nContent-Length: 1048576\r\n\r\n"[..]);
+        assert!(decode_request(&mut http, &mut input).unwrap().is_none());
+        assert!(input.capacity() < 128 * 1024);
+
+        // the bodies streamed to their handler are not limited
+        let mut http = HttpCodec {
+            limits,
+            ..streaming_codec()
+        };
+        let mut input =
+            BytesMut::from(&b"POST /upload HTTP/1.1\r\nContent-Length: 100\r\n\r\n"[..]);
+        match http.decode(&mut input).unwrap().unwrap() {
+            Message::Head(request) => assert_eq!(request.content_length, 100),
+            _ => panic!("Not a request head"),
+        }
+    }
+
+    #[test]
+    fn http_decode_chunked_malformed() {
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for body in &[
+            "zz\r\nhello\r\n0\r\n\r\n",
+            "\r\n",
+            "5\r\nhelloX\r\n0\r\n\r\n",
+            "fffffffffffffffffffffffff\r\n",
+            "0\r\nnot a trailer\r\n\r\n",
+            "0\r\n: empty\r\n\r\n",
+        ] {
+            assert_eq!(
+                rejected_status(format!("{}{}", head, body).as_bytes()),
+                Some(400)
+            );
+        }
+
+        let long_line = format!("{}5;{}", head, "x".repeat(MAX_CHUNK_LINE));
+        assert_eq!(rejected_status(long_line.as_bytes()), Some(400));
+        let long_trailers = format!("{}0\r\nX: {}", head, "x".repeat(MAX_TRAILERS));
+        assert_eq!(rejected_status(long_trailers.as_bytes()), Some(400));
+
+        assert_eq!(
+            rejected_status(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\nhello"
+            ),
+            Some(400)
+        );
+        assert_eq!(
+            rejected_status(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+            Some(501)
+        );
+        assert_eq!(
+            rejected_status(b"POST / HTTP/1.1\r\nContent-Length: five\r\n\r\nhello"),
+            Some(400)
+        );
+        assert_eq!(
+            rejected_status(
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!"
+            ),
+            Some(400)
+        );
+        assert_eq!(
+            rejected_status(
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello"
+            ),
+            None
+        );
+    }
+
+    fn streaming_codec() -> HttpCodec<i32> {
+        let mut http = HttpCodec::new(get_logger(), 0);
+        http.set_streams_body(|request| request.path == "/upload");
+        http
+    }
+
+    // The body chunks up to the end of the streamed body
+    fn decode_body(http: &mut HttpCodec<i32>, input: &mut BytesMut) -> Option<Vec<Vec<u8>>> {
+        let mut chunks = vec![];
+        loop {
+            match http.decode(input).unwrap()? {
+                Message::Chunk(chunk) => chunks.push(chunk),
+                Message::End => return Some(chunks),
+                _ => panic!("Not a body chunk"),
+            }
+        }
+    }
+
+    #[test]
+    fn http_decode_streamed() {
+        let mut input =
+            BytesMut::from(&b"POST /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\n01234"[..]);
+
+        let mut http = streaming_codec();
+        let request = match http.decode(&mut input).unwrap().unwrap() {
+            Message::Head(request) => request,
+            _ => panic!("Not a request head"),
+        };
+        assert_eq!(request.path, "/upload");
+        assert_eq!(request.content_length, 10);
+        assert_eq!(request.body, b"");
+        assert!(decode_body(&mut http, &mut input).is_none());
+
+        input.extend_from_slice(b"56789POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nok");
+        assert_eq!(
+            decode_body(&mut http, &mut input),
+            Some(vec![b"56789".to_vec()])
+        );
+        let request = decode_request(&mut http, &mut input).unwrap().unwrap();
+        assert_eq!(request.body, b"ok");
+        assert_eq!(input.to_vec(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn http_decode_streamed_chunked() {
+        let full = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nChecksum: abc\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+
+        let mut http = streaming_codec();
+        let mut input = BytesMut::new();
+        let mut messages = vec![];
+        for byte in full.iter() {
+            input.extend_from_slice(&[*byte]);
+            while let Some(message) = http.decode(&mut input).unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(input.to_vec(), Vec::<u8>::new());
+
+        match messages.remove(0) {
+            Message::Head(request) => assert_eq!(request.path, "/upload"),
+            _ => panic!("Not a request head"),
+        }
+        let mut body = vec![];
+        while let Message::Chunk(chunk) = messages.remove(0) {
+            body.extend_from_slice(&chunk);
+        }
+        assert_eq!(body, b"hello, world");
+        match messages.remove(0) {
+            Message::Request(request) => assert_eq!(request.method, "GET"),
+            _ => panic!("Not a whole request"),
+        }
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn http_decode_streamed_malformed() {
+        let mut input = BytesMut::from(
+            &b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloX\r\n"[..],
+        );
+
+        let mut http = streaming_codec();
+        http.decode(&mut input).unwrap().unwrap();
+        match http.decode(&mut input).unwrap().unwrap() {
+            Message::Chunk(chunk) => assert_eq!(chunk, b"hello"),
+            _ => panic!("Not a body chunk"),
+        }
+        let error = http.decode(&mut input).err().unwrap();
+        let error = error.into_inner().unwrap().downcast::<HttpError>().unwrap();
+        assert_eq!(error.status_code, 400);
+    }
+
+    fn encode(response: Response) -> io::Result<Vec<u8>> {
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let mut output = BytesMut::new();
+        http.encode(response.into(), &mut output)?;
+        Ok(output.to_vec())
+    }
+
+    #[test]
+    fn http_encode_status_code() {
+        for (status_code, status_line) in &[
+            (200, "HTTP/1.1 200 OK\r\n"),
+            (201, "HTTP/1.1 201 Created\r\n"),
+            (404, "HTTP/1.1 404 Not Found\r\n"),
+            (418, "HTTP/1.1 418 I'm a teapot\r\n"),
+            (500, "HTTP/1.1 500 Internal Server Error\r\n"),
+            (599, "HTTP/1.1 599 \r\n"),
+        ] {
+            let output = encode(Response {
+                status_code: *status_code,
+                reason_phrase: None,
+                headers: HashMap::new(),
+                content_type: None,
+                body: vec![],
+                stream: None,
+            })
+            .unwrap();
+
+            assert!(output.starts_with(status_line.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn http_encode_custom_reason_phrase() {
+        let output = encode(Response {
+            status_code: 404,
+            reason_phrase: Some("Nothing To See Here".to_owned()),
+            headers: HashMap::new(),
+            content_type: None,
+            body: vec![],
+            stream: None,
+        })
+        .unwrap();
+
+        assert!(output.starts_with(b"HTTP/1.1 404 Nothing To See Here\r\n"));
+
+        let output = encode(Response {
+            status_code: 200,
+            reason_phrase: Some("OK\r\nSet-Cookie: a=b".to_owned()),
+            headers: HashMap::new(),
+            content_type: None,
+            body: vec![],
+            stream: None,
+        });
+
+        assert!(output.is_err());
+    }
+
+    #[test]
+    fn http_encode_invalid_status_code() {
+        for status_code in &[0, 99, 600, 1000] {
+            let output = encode(Response {
+                status_code: *status_code,
+                reason_phrase: None,
+                headers: HashMap::new(),
+                content_type: None,
+                body: vec![],
+                stream: None,
+            });
+
+            assert!(output.is_err());
+        }
+    }
+
+    fn encode_headers(headers: Vec<(&str, &str)>) -> io::Result<String> {
+        let mut response = Response {
+            status_code: 200,
+            reason_phrase: None,
+            headers: HashMap::new(),
+            content_type: Some("text/plain".to_owned()),
+            body: b"body".to_vec(),
+            stream: None,
+        };
+        for (name, value) in headers {
+            response.add_header(name, value);
+        }
+        encode(response).map(|output| String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn http_encode_headers() {
+        let output = encode_headers(vec![
+            ("X-Request-Id", "42"),
+            ("Set-Cookie", "a=1"),
+            ("Set-Cookie", "b=2"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\n\
+             Set-Cookie: a=1\r\n\
+             Set-Cookie: b=2\r\n\
+             X-Request-Id: 42\r\n\
+             Connection: keep-alive\r\n\
+             Content-Length: 4\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             body"
+        );
+    }
+
+    #[test]
+    fn http_encode_headers_override_connection() {
+        let output = encode_headers(vec![("Connection", "close")]).unwrap();
+
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(!output.contains("keep-alive"));
+    }
+
+    #[test]
+    fn http_encode_headers_reject_injection() {
+        assert!(encode_headers(vec![("X-Foo", "bar\r\nSet-Cookie: a=1")]).is_err());
+        assert!(encode_headers(vec![("X-Foo", "bar\nbaz")]).is_err());
+        assert!(encode_headers(vec![("X-Foo\r\nSet-Cookie", "a=1")]).is_err());
+        assert!(encode_headers(vec![("X Foo", "bar")]).is_err());
+        assert!(encode_headers(vec![("", "bar")]).is_err());
+    }
+
+    #[test]
+    fn http_encode_headers_content_length() {
+        let output = encode_headers(vec![("content-length", "4")]).unwrap();
+        assert_eq!(output.matches("ontent-").count(), 2);
+        assert!(output.contains("Content-Length: 4\r\n"));
+
+        assert!(encode_headers(vec![("Content-Length", "100")]).is_err());
+        assert!(encode_headers(vec![("Content-Length", "4"), ("Content-Length", "5")]).is_err());
+        assert!(encode_headers(vec![("Content-Type", "application/json")]).is_err());
+    }
+
+    #[test]
+    fn http_encode_binary_body() {
+        // A 1x1 PNG: its signature alone contains \r\n, \x1a and \n
+        let png: Vec<u8> = vec![
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00,
+            0x00, 0x1f, 0x15, 0xc4, 0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78,
+            0x9c, 0x63, 0xf8, 0xcf, 0xc0, 0xf0, 0x1f, 0x00, 0x05, 0x00, 0x01, 0xff, 0x89, 0x99,
+            0x3d, 0x1d, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+        ];
+
+        let output = encode(Response {
+            status_code: 200,
+            reason_phrase: None,
+            headers: HashMap::new(),
+            content_type: Some("image/png".to_owned()),
+            body: png.clone(),
+            stream: None,
+        })
+        .unwrap();
+
+        let header_length = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(output[..header_length].to_vec()).unwrap();
+        assert!(head.contains(&format!("Content-Length: {}\r\n", png.len())));
+        assert_eq!(&output[header_length..], &png[..]);
+    }
+
+    #[test]
+    fn http_encode_head_response() {
+        let mut input = BytesMut::new();
+        input.extend_from_slice(b"HEAD / HTTP/1.1\r\nHost: localhost:8880\r\n\r\nGET / HTTP/1.1\r\nHost: localhost:8880\r\n\r\n");
+
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let head_request = decode_request(&mut http, &mut input).unwrap().unwrap();
+        let get_request = decode_request(&mut http, &mut input).unwrap().unwrap();
+        assert_eq!(head_request.method, "HEAD");
+        assert_eq!(get_request.method, "GET");
+
+        let mut output = BytesMut::new();
+        for _ in 0..2 {
+            let response = Response {
+                status_code: 200,
+                reason_phrase: None,
+                headers: HashMap::new(),
+                content_type: None,
+                body: b"body".to_vec(),
+                stream: None,
+            };
+            http.encode(response.into(), &mut output).unwrap();
+        }
+
+        assert_eq!(
+            output.to_vec(),
+            b"HTTP/1.1 200 OK\r\n\
+              Connection: keep-alive\r\n\
+              Content-Length: 4\r\n\
+              \r\n\
+              HTTP/1.1 200 OK\r\n\
+              Connection: keep-alive\r\n\
+              Content-Length: 4\r\n\
+              \r\n\
+              body"
+                .to_vec()
+        );
+    }
+
+    fn streamed_response(status_code: u16) -> Response {
+        Response {
+            status_code,
+            ..Response::streamed("text/csv", futures::stream::empty())
+        }
+    }
+
+    #[test]
+    fn http_encode_streamed() {
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let mut output = BytesMut::new();
+        http.encode(Frame::Head(streamed_response(200)), &mut output)
+            .unwrap();
+        assert!(http.is_streaming());
+        for chunk in &[&b"id,name\r\n"[..], b"", &[b'x'; 26][..]] {
+            http.encode(Frame::Chunk(chunk.to_vec()), &mut output)
+                .unwrap();
+        }
+        http.encode(Frame::End, &mut output).unwrap();
+        assert!(!http.is_streaming());
+
+        assert_eq!(
+            output.to_vec(),
+            [
+                &b"HTTP/1.1 200 OK\r\n\
+                   Connection: keep-alive\r\n\
+                   Transfer-Encoding: chunked\r\n\
+                   Content-Type: text/csv\r\n\
+                   \r\n\
+                   9\r\nid,name\r\n\r\n\
+                   1A\r\n"[..],
+                &[b'x'; 26][..],
+                b"\r\n0\r\n\r\n",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn http_encode_streamed_without_body() {
+        let mut input = BytesMut::from(&b"HEAD / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n"[..]);
+        let mut http = HttpCodec::new(get_logger(), 0);
+        decode_request(&mut http, &mut input).unwrap().unwrap();
+        decode_request(&mut http, &mut input).unwrap().unwrap();
+
+        let mut output = BytesMut::new();
+        http.encode(Frame::Head(streamed_response(200)), &mut output)
+            .unwrap();
+        assert!(!http.is_streaming());
+        http.encode(Frame::Chunk(b"dropped".to_vec()), &mut output)
+            .unwrap();
+        http.encode(Frame::End, &mut output).unwrap();
+        http.encode(Frame::Head(streamed_response(304)), &mut output)
+            .unwrap();
+        assert!(!http.is_streaming());
+
+        assert_eq!(
+            output.to_vec(),
+            b"HTTP/1.1 200 OK\r\n\
+              Connection: keep-alive\r\n\
+              Transfer-Encoding: chunked\r\n\
+              Content-Type: text/csv\r\n\
+              \r\n\
+              HTTP/1.1 304 Not Modified\r\n\
+              Connection: keep-alive\r\n\
+              Content-Type: text/csv\r\n\
+              \r\n"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn http_encode_streamed_invalid() {
+        let mut http = HttpCodec::new(get_logger(), 0);
+        let mut output = BytesMut::new();
+        assert!(http
+            .encode(Frame::Response(streamed_response(200)), &mut output)
+            .is_err());
+
+        let mut response = streamed_response(200);
+        response.body = b"body".to_vec();
+        assert!(http.encode(Frame::Head(response), &mut output).is_err());
+
+        let mut response = streamed_response(200);
+        response.set_header("Content-Length", "4");
+        assert!(http.encode(Frame::Head(response), &mut output).is_err());
+
+        let mut response = streamed_response(200);
+        response.set_header("Transfer-Encoding", "chunked");
+        assert!(http.encode(Frame::Head(response), &mut output).is_err());
+    }
+
+    #[test]
+    fn http_encode_head_content_length() {
+        let mut input = BytesMut::from(&b"HEAD / HTTP/1.1\r\n\r\nHEAD / HTTP/1.1\r\n\r\n"[..]);
+        let mut http = HttpCodec::new(get_logger(), 0);
+        decode_request(&mut http, &mut input).unwrap().unwrap();
+        decode_request(&mut http, &mut input).unwrap().unwrap();
+
+        let mut output = BytesMut::new();
+        let mut response = Vec::new().into_response();
+        response.set_header("Content-Length", "1234");
+        http.encode(response.into(), &mut output).unwrap();
+        http.encode(Vec::new().into_response().into(), &mut output)
+            .unwrap();
+
+        assert_eq!(
+            output.to_vec(),
+            b"HTTP/1.1 200 OK\r\n\
+              Connection: keep-alive\r\n\
+              Content-Length: 1234\r\n\
+              Content-Type: application/octet-stream\r\n\
+              \r\n\
+              HTTP/1.1 200 OK\r\n\
+              Connection: keep-alive\r\n\
+              Content-Type: application/octet-stream\r\n\
+              \r\n"
+                .to_vec()
+        );
+
+        let mut response = Vec::new().into_response();
+        response.add_header("Content-Length", "1");
+        response.add_header("Content-Length", "2");
+        assert!(check_response(&response, true).is_err());
+        response.set_header("Content-Length", "many");
+        assert!(check_response(&response, true).is_err());
+    }
+
+    #[test]
+    fn http_encode_no_content() {
+        let output = encode(Response {
+            status_code: 204,
+            reason_phrase: None,
+            headers: HashMap::new(),
+            content_type: None,
+            body: vec![],
+            stream: None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            output,
+            b"HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n".to_vec()
+        );
+    }
 }
@@ -3,15 +3,10 @@
 use tokio;
 // extern crate tokio_signal;
 
-use core::hash::Hash;
-use core::hash::Hasher;
-use regex::Regex;
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use bravery_router::{add, create_root_node, find, optimize, Node};
-
-use objekt;
+use std::io;
 use std::net::SocketAddr;
 use std::str;
 use tokio::prelude::*;
@@ -30,22 +25,31 @@ use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
 use sloggers::Build;
 
+use futures::channel::mpsc;
 use futures::executor::block_on;
+use futures::future::{self, Either};
+use futures::pin_mut;
 
+pub mod extract;
+pub mod handler;
 pub mod http;
+pub mod middleware;
 pub mod request;
 pub mod response;
+mod router;
+pub mod scope;
 
-pub use self::http::HttpCodec;
+pub use self::extract::{FromRequest, Header, HeaderName, Json, Path, Query, State};
+pub use self::handler::{AsyncHandler, Endpoint, Handler, HandlerFuture, IntoEndpoint, Streamed};
+use self::http::check_response;
+pub use self::http::{Frame, HttpCodec, Limits, Message};
+pub use self::middleware::{Middleware, MiddlewareFuture, Next};
 pub use self::request::Request;
-pub use self::response::Response;
-
-#[derive(Debug)]
-struct MatchedRouter {
-    s: String,
-    regex: Regex,
-    method: String,
-}
+pub use self::response::{BodyStream, IntoResponse, Response};
+pub use self::router::UrlForError;
+use self::router::{build_path, routed_path, Found, Router};
+pub use self::scope::Scope;
+use self::scope::{join_path, Scoped};
 
 #[derive(Serialize)]
 pub struct HttpError {
@@ -54,73 +58,240 @@ pub struct HttpError {
     pub details: String,
 }
 
-impl PartialEq for MatchedRouter {
-    fn eq(&self, other: &MatchedRouter) -> bool {
-        self.s == other.s && self.method == other.method
-    }
-}
-impl Eq for MatchedRouter {}
-
-impl Hash for MatchedRouter {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.s.hash(state);
-        self.method.hash(state);
-    }
-}
-
 impl std::fmt::Debug for HttpError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "HTTPError")
     }
 }
 
-pub trait Handler<T: Clone + Send + Sync>: objekt::Clone + Sync + Send {
-    fn invoke(&self, req: Request<T>) -> Result<Response, HttpError>;
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status_code, self.error_message)
+    }
 }
-objekt::clone_trait_object!(<T: Clone + Send + Sync> Handler<T>);
+
+impl std::error::Error for HttpError {}
+
+// Chunks of a streamed request body read ahead of the handler
+const STREAMED_BODY_CHUNKS: usize = 4;
 
 async fn process_socket<T: Clone + Sync + Send + Unpin>(
     app: Arc<App<T>>,
     socket: TcpStream,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut framed = Framed::<TcpStream, HttpCodec<T>>::new(
-        socket,
-        HttpCodec {
-            logger: app.logger.clone(),
-            with_headers: true,
-            with_query_string: true,
-            context: app.context.clone(),
-        },
-    );
+    let mut codec = HttpCodec::new(app.logger.clone(), app.context.clone());
+    codec.limits = app.limits;
+    let streams_body = app.clone();
+    codec.set_streams_body(move |request| streams_body.streams_body(request));
+    let mut framed = Framed::<TcpStream, HttpCodec<T>>::new(socket, codec);
+
+    while let Some(message) = framed.next().await {
+        match message {
+            Ok(Message::Request(request)) => {
+                let response = resolve(&app, request).await;
+                respond(&mut framed, response).await?;
+            }
+            Ok(Message::Head(mut request)) => {
+                let (sender, receiver) = mpsc::channel(STREAMED_BODY_CHUNKS);
+                request.stream = Some(Box::pin(receiver));
+                let mut upload = Upload::Reading(sender, None);
 
-    while let Some(request) = framed.next().await {
-        match request {
-            Ok(request) => {
-                let response = resolve(&app, request).await?;
-                framed.send(response).await?;
+                let resolving = resolve(&app, request);
+                pin_mut!(resolving);
+                let response = loop {
+                    let forwarding = forward_body(&mut framed, &mut upload);
+                    pin_mut!(forwarding);
+                    if let Either::Left((response, _)) =
+                        future::select(resolving.as_mut(), forwarding).await
+                    {
+                        break response;
+                    }
+                };
+                if !respond_uploading(&mut framed, response, &mut upload).await? {
+                    return Ok(());
+                }
+            }
+            Ok(Message::Chunk(_)) | Ok(Message::End) => {
+                unreachable!("A streamed request body is read by forward_body")
             }
             Err(e) => {
                 // Connection reset by peer
                 if e.raw_os_error() == Some(54) {
-                    return Ok(())
+                    return Ok(());
                 }
-                return Err(e.into())
-            },
+                // A request the codec refused: answer it, the stream can't be read further
+                let refused = e
+                    .get_ref()
+                    .filter(|error| error.is::<HttpError>())
+                    .is_some();
+                if !refused {
+                    return Err(e.into());
+                }
+                let error = e.into_inner().unwrap().downcast::<HttpError>().unwrap();
+                let mut response = error.into_response();
+                response.set_header("Connection", "close");
+                framed.send(Frame::Response(response)).await?;
+                return Ok(());
+            }
         }
     }
 
     Ok(())
 }
 
+async fn respond<T: Clone + Sync + Send + Unpin>(
+    framed: &mut Framed<TcpStream, HttpCodec<T>>,
+    mut response: Response,
+) -> io::Result<()> {
+    let mut stream = match response.stream.take() {
+        Some(stream) => stream,
+        None => return framed.send(Frame::Response(response)).await,
+    };
+    framed.send(Frame::Head(response)).await?;
+    // Every chunk waits for the previous one to be written
+    while framed.codec().is_streaming() {
+        match stream.next().await {
+            Some(chunk) => framed.send(Frame::Chunk(chunk?)).await?,
+            None => framed.send(Frame::End).await?,
+        }
+    }
+    Ok(())
+}
+
+/// How far the body streamed to a handler has been read
+enum Upload {
+    /// Chunks are left, the last one read may wait for room in the handler's stream
+    Reading(mpsc::Sender<io::Result<Vec<u8>>>, Option<Vec<u8>>),
+    /// The body can't be read, the handler is yet to be told why
+    Failing(mpsc::Sender<io::Result<Vec<u8>>>, io::Error),
+    Done,
+    /// The handler dropped the stream or the body can't be read:
+    /// the rest of the connection is not read either
+    Abandoned,
+}
+
+/// Moves the streamed request body one step further: reads a chunk, or hands
+/// the one read to the handler. Never completes once there is nothing left to do,
+/// and can be dropped while waiting without losing a chunk
+async fn forward_body<T: Clone + Sync + Send + Unpin>(
+    framed: &mut Framed<TcpStream, HttpCodec<T>>,
+    upload: &mut Upload,
+) {
+    match upload {
+        Upload::Reading(sender, pending @ Some(_)) => {
+            if future::poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+                *upload = Upload::Abandoned;
+            } else {
+                let _ = sender.start_send(Ok(pending.take().unwrap()));
+            }
+        }
+        Upload::Reading(_, pending) => match framed.next().await {
+            Some(Ok(Message::Chunk(chunk))) => *pending = Some(chunk),
+            Some(Ok(Message::End)) => *upload = Upload::Done,
+            Some(Ok(_)) => unreachable!("A request body ends before the next request"),
+            Some(Err(error)) => *upload = upload.fail(error),
+            None => *upload = upload.fail(io::ErrorKind::UnexpectedEof.into()),
+        },
+        Upload::Failing(sender, error) => {
+            if future::poll_fn(|cx| sender.poll_ready(cx)).await.is_ok() {
+                let copy = io::Error::new(error.kind(), error.to_string());
+                let _ = sender.start_send(Err(copy));
+            }
+            *upload = Upload::Abandoned;
+        }
+        Upload::Done | Upload::Abandoned => future::pending().await,
+    }
+}
+
+impl Upload {
+    fn is_done(&self) -> bool {
+        match self {
+            Upload::Done => true,
+            Upload::Reading(..) | Upload::Failing(..) | Upload::Abandoned => false,
+        }
+    }
+
+    fn fail(&mut self, error: io::Error) -> Upload {
+        match std::mem::replace(self, Upload::Abandoned) {
+            Upload::Reading(sender, _) => Upload::Failing(sender, error),
+            _ => Upload::Abandoned,
+        }
+    }
+}
+
+/// Writes the response to a request whose body is streamed, forwarding what is
+/// left of the body meanwhile as the response stream may be reading it.
+/// Gives whether the connection can be kept for the next request
+async fn respond_uploading<T: Clone + Sync + Send + Unpin>(
+    framed: &mut Framed<TcpStream, HttpCodec<T>>,
+    mut response: Response,
+    upload: &mut Upload,
+) -> io::Result<bool> {
+    let mut stream = match response.stream.take() {
+        Some(stream) => stream,
+        None => {
+            // Nothing reads the rest of the body: close rather than drain it
+            let done = upload.is_done();
+            if !done {
+                response.set_header("Connection", "close");
+            }
+            framed.send(Frame::Response(response)).await?;
+            return Ok(done);
+        }
+    };
+    if let Upload::Abandoned = upload {
+        response.set_header("Connection", "close");
+    }
+    framed.send(Frame::Head(response)).await?;
+    while framed.codec().is_streaming() {
+        let next = {
+            let forwarding = forward_body(framed, upload);
+            pin_mut!(forwarding);
+            match future::select(stream.next(), forwarding).await {
+                Either::Left((next, _)) => Some(next),
+                Either::Right(_) => None,
+            }
+        };
+        match next {
+            Some(Some(chunk)) => framed.send(Frame::Chunk(chunk?)).await?,
+            Some(None) => framed.send(Frame::End).await?,
+            None => {}
+        }
+    }
+    Ok(upload.is_done())
+}
+
 #[derive(Clone)]
 pub struct App<T: 'static + Clone + Sync + Send> {
-    get_router: Node<usize>,
-    get_handlers: Vec<Box<dyn Handler<T>>>,
-    post_router: Node<usize>,
-    post_handlers: Vec<Box<dyn Handler<T>>>,
+    router: Router<Scoped<T>>,
     logger: slog::Logger,
     context: T,
-    not_found: Box<dyn Handler<T>>,
+    not_found: Endpoint<T>,
+    // (prefix, handler): the longest prefix matching the path wins over `not_found`
+    prefixed_not_found: Vec<(String, Scoped<T>)>,
+    method_not_allowed: Endpoint<T>,
+    default_options: Endpoint<T>,
+    not_implemented: Endpoint<T>,
+    middlewares: Vec<Box<dyn Middleware<T>>>,
+    // route name -> path pattern, for `url_for`
+    names: HashMap<String, String>,
+    // (host name or `*.` wildcard, app answering for it)
+    hosts: Vec<(String, App<T>)>,
+    trailing_slash: TrailingSlash,
+    normalize_paths: bool,
+    limits: Limits,
+}
+
+/// What to do with a path matching no route while the same path with or without
+/// a trailing slash does, like `/users/` for `/users`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingSlash {
+    /// Nothing: the path is not found
+    Strict,
+    /// Redirects to the other path with this status code, usually 301 or 308
+    Redirect(u16),
+    /// Routes the request to the other path
+    Match,
 }
 
 fn get_logger() -> slog::Logger {
@@ -133,13 +304,20 @@ fn get_logger() -> slog::Logger {
 impl Default for App<EmptyState> {
     fn default() -> Self {
         App {
-            get_router: create_root_node(),
-            get_handlers: vec![],
-            post_router: create_root_node(),
-            post_handlers: vec![],
+            router: Router::new(),
             logger: get_logger(),
             context: EmptyState {},
-            not_found: Box::new(HandlerFor404 {}),
+            not_found: Box::new(HandlerFor404 {}).into_endpoint(),
+            prefixed_not_found: vec![],
+            method_not_allowed: Box::new(HandlerFor405 {}).into_endpoint(),
+            default_options: Box::new(HandlerForOptions {}).into_endpoint(),
+            not_implemented: Box::new(HandlerFor501 {}).into_endpoint(),
+            middlewares: vec![],
+            names: HashMap::new(),
+            hosts: vec![],
+            trailing_slash: TrailingSlash::Strict,
+            normalize_paths: false,
+            limits: Limits::default(),
         }
     }
 }
@@ -147,28 +325,265 @@ impl Default for App<EmptyState> {
 impl<T: Clone + Send + Sync + Unpin> App<T> {
     pub fn new_with_state(t: T) -> Self {
         App {
-            get_router: create_root_node(),
-            get_handlers: vec![],
-            post_router: create_root_node(),
-            post_handlers: vec![],
+            router: Router::new(),
             logger: get_logger(),
             context: t,
-            not_found: Box::new(HandlerFor404 {}),
+            not_found: Box::new(HandlerFor404 {}).into_endpoint(),
+            prefixed_not_found: vec![],
+            method_not_allowed: Box::new(HandlerFor405 {}).into_endpoint(),
+            default_options: Box::new(HandlerForOptions {}).into_endpoint(),
+            not_implemented: Box::new(HandlerFor501 {}).into_endpoint(),
+            middlewares: vec![],
+            names: HashMap::new(),
+            hosts: vec![],
+            trailing_slash: TrailingSlash::Strict,
+            normalize_paths: false,
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn get<M>(self: &mut App<T>, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("GET", path, handler);
+    }
+
+    pub fn post<M>(self: &mut App<T>, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("POST", path, handler);
+    }
+
+    pub fn put<M>(self: &mut App<T>, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("PUT", path, handler);
+    }
+
+    pub fn delete<M>(self: &mut App<T>, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("DELETE", path, handler);
+    }
+
+    pub fn patch<M>(self: &mut App<T>, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("PATCH", path, handler);
+    }
+
+    pub fn head<M>(self: &mut App<T>, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("HEAD", path, handler);
+    }
+
+    pub fn options<M>(self: &mut App<T>, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("OPTIONS", path, handler);
+    }
+
+    /// Replaces the handler invoked when no route matches the path
+    pub fn set_not_found<M>(self: &mut App<T>, handler: impl IntoEndpoint<T, M>) {
+        self.set_not_found_for("", handler);
+    }
+
+    /// Like `set_not_found`, but only for `prefix` and the paths below it:
+    /// `/api` covers `/api` and `/api/users`, not `/apis`
+    pub fn set_not_found_for<M>(self: &mut App<T>, prefix: &str, handler: impl IntoEndpoint<T, M>) {
+        self.add_not_found(prefix, Scoped::new(handler.into_endpoint()));
+    }
+
+    fn add_not_found(self: &mut App<T>, prefix: &str, handler: Scoped<T>) {
+        let prefix = prefix.trim_end_matches('/').to_owned();
+        self.prefixed_not_found.retain(|(p, _)| p != &prefix);
+        self.prefixed_not_found.push((prefix, handler));
+    }
+
+    fn not_found_for(self: &App<T>, path: &str) -> (&Endpoint<T>, &[Box<dyn Middleware<T>>]) {
+        self.prefixed_not_found
+            .iter()
+            .filter(|(prefix, _)| {
+                path.starts_with(prefix.as_str())
+                    && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or((&self.not_found, &[]), |(_, handler)| {
+                (&handler.endpoint, &handler.middlewares)
+            })
+    }
+
+    /// Runs `middleware` around every request, the ones answered by the
+    /// not-found and method-not-allowed handlers included.
+    /// Middlewares run in the order they are added.
+    pub fn wrap(self: &mut App<T>, middleware: impl Middleware<T> + 'static) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Replaces the handler invoked when the path exists only under other methods.
+    /// The `Allow` header is added to its response unless the handler sets it.
+    pub fn set_method_not_allowed<M>(self: &mut App<T>, handler: impl IntoEndpoint<T, M>) {
+        self.method_not_allowed = handler.into_endpoint();
+    }
+
+    /// Registers `handler` for any method, extension methods like `PURGE` included.
+    /// Panics if `method` is not a valid HTTP method token, or if a route registered
+    /// before for `method` matches the same paths, like `/users/:id` and `/users/:name`.
+    pub fn route<M>(self: &mut App<T>, method: &str, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.add(method, path, Scoped::new(handler.into_endpoint()));
+    }
+
+    fn add(self: &mut App<T>, method: &str, path: &str, handler: Scoped<T>) {
+        if ::http::Method::from_bytes(method.as_bytes()).is_err() {
+            panic!("Invalid HTTP method {:?} for path {:?}", method, path);
+        }
+        self.router.add(method, path, handler);
+    }
+
+    /// Like `get`, naming the route for `url_for`
+    pub fn get_named<M>(
+        self: &mut App<T>,
+        name: &str,
+        path: &str,
+        handler: impl IntoEndpoint<T, M>,
+    ) {
+        self.route_named(name, "GET", path, handler);
+    }
+
+    /// Like `route`, naming the route for `url_for`.
+    /// Panics if `name` is already taken.
+    pub fn route_named<M>(
+        self: &mut App<T>,
+        name: &str,
+        method: &str,
+        path: &str,
+        handler: impl IntoEndpoint<T, M>,
+    ) {
+        self.route(method, path, handler);
+        self.add_name(name, path);
+    }
+
+    fn add_name(self: &mut App<T>, name: &str, path: &str) {
+        if let Some(existing) = self.names.insert(name.to_owned(), path.to_owned()) {
+            panic!("Route name {:?} is already used by {:?}", name, existing);
+        }
+    }
+
+    /// The path of the route named `name`, its params filled with the percent-encoded
+    /// `params` values: `url_for("user", &[("id", "42")])` gives `/users/42` for `/users/:id`
+    pub fn url_for(
+        self: &App<T>,
+        name: &str,
+        params: &[(&str, &str)],
+    ) -> Result<String, UrlForError> {
+        let pattern = self
+            .names
+            .get(name)
+            .ok_or_else(|| UrlForError::UnknownRoute(name.to_owned()))?;
+        build_path(pattern, params)
+    }
+
+    /// (method, pattern, handler name) of every route, in registration order
+    pub fn routes(self: &App<T>) -> impl Iterator<Item = (&str, &str, &'static str)> {
+        self.router
+            .routes()
+            .map(|(method, path, handler)| (method, path, handler.endpoint.name()))
+    }
+
+    /// Registers the routes added by `build` below `prefix`.
+    /// The middlewares and the not-found handler set on the scope only apply to them
+    pub fn scope(self: &mut App<T>, prefix: &str, build: impl FnOnce(&mut Scope<T>)) {
+        let mut scope = Scope::new(prefix);
+        build(&mut scope);
+        scope.close();
+        for (method, path, handler) in scope.routes {
+            self.add(&method, &path, handler);
+        }
+        for (prefix, handler) in scope.not_found {
+            self.add_not_found(&prefix, handler);
+        }
+        for (name, path) in scope.names {
+            self.add_name(&name, &path);
+        }
+    }
+
+    /// Registers the routes of `app` below `prefix`, like a scope.
+    /// Its middlewares and not-found handlers come along; its state, logger and
+    /// other fallback handlers are dropped in favour of the ones of this `App`
+    pub fn mount(self: &mut App<T>, prefix: &str, app: App<T>) {
+        for (method, path, handler) in app.router.routes() {
+            let mut handler = handler.clone();
+            handler.within(&app.middlewares);
+            self.add(method, &join_path(prefix, path), handler);
         }
+        for (path, handler) in &app.prefixed_not_found {
+            let mut handler = handler.clone();
+            handler.within(&app.middlewares);
+            self.add_not_found(&join_path(prefix, path), handler);
+        }
+        for (name, path) in &app.names {
+            self.add_name(name, &join_path(prefix, path));
+        }
+    }
+
+    /// Sets how paths differing from a route by a trailing slash are handled,
+    /// `TrailingSlash::Strict` by default
+    pub fn set_trailing_slash(self: &mut App<T>, policy: TrailingSlash) {
+        self.trailing_slash = policy;
+    }
+
+    /// When set, duplicate slashes are collapsed and `.` and `..` segments resolved
+    /// before routing, so `//users/./42` matches `/users/42`
+    pub fn set_normalize_paths(self: &mut App<T>, normalize_paths: bool) {
+        self.normalize_paths = normalize_paths;
+    }
+
+    /// The request sizes the server accepts. They apply to every host:
+    /// the limits of the apps given to `host` are not used
+    pub fn set_limits(self: &mut App<T>, limits: Limits) {
+        self.limits = limits;
     }
 
-    pub fn get(self: &mut App<T>, path: &str, handler: Box<dyn Handler<T>>) {
-        add(&mut self.get_router, path, self.get_handlers.len());
-        self.get_handlers.push(handler);
+    /// When set, the static segments of the routes match regardless of case.
+    /// Params keep the case of the request path.
+    /// Panics if two routes registered before become the same, like `/Users` and `/users`
+    pub fn set_case_insensitive(self: &mut App<T>, case_insensitive: bool) {
+        self.router.set_case_insensitive(case_insensitive);
     }
 
-    pub fn post(self: &mut App<T>, path: &str, handler: Box<dyn Handler<T>>) {
-        add(&mut self.post_router, path, self.post_handlers.len());
-        self.post_handlers.push(handler);
+    /// Lets `app` answer the requests whose `Host` header is `host`, port aside.
+    /// `host` is either a name like `example.test` or a wildcard like `*.example.test`,
+    /// matching any subdomain. An exact name wins over the wildcards, and the longest
+    /// wildcard wins over the others; this `App` answers when none matches.
+    /// `app` runs on its own: its middlewares, not-found handler and so on, but the
+    /// state and logger of this `App`. Panics if `host` is already registered.
+    pub fn host(self: &mut App<T>, host: &str, app: App<T>) {
+        let host = host.to_lowercase();
+        if self.hosts.iter().any(|(h, _)| h == &host) {
+            panic!("Host {:?} is already registered", host);
+        }
+        self.hosts.push((host, app));
+    }
+
+    fn for_host(self: &App<T>, request: &Request<T>) -> &App<T> {
+        let host = match request.headers.get("host") {
+            Some(host) => host_name(host),
+            None => return self,
+        };
+        let exact = self.hosts.iter().find(|(pattern, _)| pattern == &host);
+        exact
+            .or_else(|| {
+                self.hosts
+                    .iter()
+                    .filter(|(pattern, _)| {
+                        pattern.starts_with("*.")
+                            && host.len() > pattern.len() - 1
+                            && host.ends_with(&pattern[1..])
+                    })
+                    .max_by_key(|(pattern, _)| pattern.len())
+            })
+            .map_or(self, |(_, app)| app)
+    }
+
+    /// Whether `request` is routed to a handler wrapped in `Streamed`
+    fn streams_body(self: &App<T>, request: &Request<T>) -> bool {
+        let app = self.for_host(request);
+        route_path(app, request)
+            .ok()
+            .and_then(|path| find_route(app, &request.method, &path))
+            .filter(|found| found.value.endpoint.streams_body())
+            .is_some()
     }
 
     pub fn inject(self: &App<T>, request: Request<T>) -> Response {
-        block_on(resolve(self, request)).unwrap()
+        block_on(resolve(self, request))
     }
 
     pub fn create_request(
@@ -186,7 +601,11 @@ impl<T: Clone + Send + Sync + Unpin> App<T> {
             header_lenght: 0,
             query_string: query_string.to_owned(),
             headers: HashMap::new(),
+            trailers: HashMap::new(),
+            params: HashMap::new(),
+            tail: None,
             body,
+            stream: None,
             logger: self.logger.clone(),
             context: self.context.clone(),
         }
@@ -197,8 +616,10 @@ impl<T: Clone + Send + Sync + Unpin> App<T> {
         rt.block_on(async {
             let mut incoming = TcpListener::bind(&addr).await?.incoming();
 
-            self.post_router = optimize(self.post_router);
-            self.get_router = optimize(self.get_router);
+            self.router.optimize();
+            for (_, app) in self.hosts.iter_mut() {
+                app.router.optimize();
+            }
 
             let app = Arc::new(self);
             while let Some(Ok(stream)) = incoming.next().await {
@@ -215,54 +636,280 @@ impl<T: Clone + Send + Sync + Unpin> App<T> {
     }
 }
 
+#[derive(Clone)]
+struct HandlerFor405 {}
+impl<T: Clone + Sync + Send> Handler<T> for HandlerFor405 {
+    fn invoke(&self, _req: Request<T>) -> Result<Response, HttpError> {
+        Ok(Response {
+            status_code: 405,
+            reason_phrase: None,
+            content_type: Some("text/html".to_owned()),
+            body: "405 Handler".to_owned().into_bytes(),
+            headers: HashMap::new(),
+            stream: None,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct HandlerForOptions {}
+impl<T: Clone + Sync + Send> Handler<T> for HandlerForOptions {
+    fn invoke(&self, _req: Request<T>) -> Result<Response, HttpError> {
+        Ok(Response {
+            status_code: 204,
+            reason_phrase: None,
+            content_type: None,
+            body: vec![],
+            headers: HashMap::new(),
+            stream: None,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct HandlerFor501 {}
+impl<T: Clone + Sync + Send> Handler<T> for HandlerFor501 {
+    fn invoke(&self, _req: Request<T>) -> Result<Response, HttpError> {
+        Ok(Response {
+            status_code: 501,
+            reason_phrase: None,
+            content_type: Some("text/html".to_owned()),
+            body: "501 Handler".to_owned().into_bytes(),
+            headers: HashMap::new(),
+            stream: None,
+        })
+    }
+}
+
+const STANDARD_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
 #[derive(Clone)]
 struct HandlerFor404 {}
 impl<T: Clone + Sync + Send> Handler<T> for HandlerFor404 {
     fn invoke(&self, _req: Request<T>) -> Result<Response, HttpError> {
         Ok(Response {
             status_code: 404,
+            reason_phrase: None,
             content_type: Some("text/html".to_owned()),
             body: "404 Handler".to_owned().into_bytes(),
             headers: HashMap::new(),
+            stream: None,
         })
     }
 }
 
-use percent_encoding::percent_decode_str;
+#[derive(Clone)]
+struct HandlerForRedirect {
+    status_code: u16,
+    location: String,
+}
+impl<T: Clone + Sync + Send> Handler<T> for HandlerForRedirect {
+    fn invoke(&self, _req: Request<T>) -> Result<Response, HttpError> {
+        let mut response = Response {
+            status_code: self.status_code,
+            reason_phrase: None,
+            content_type: None,
+            body: vec![],
+            headers: HashMap::new(),
+            stream: None,
+        };
+        response.set_header("Location", &self.location);
+        Ok(response)
+    }
+}
+
+/// Collapses duplicate slashes and resolves the `.` and `..` segments of `path`,
+/// spelled with `%2E` as well
+fn normalize_path(path: &str) -> String {
+    let dots = |segment: &str| segment.replace("%2e", ".").replace("%2E", ".");
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/') {
+        match dots(segment).as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let last = dots(path.rsplit('/').next().unwrap_or(""));
+    let trailing_slash = last.is_empty() || last == "." || last == "..";
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// `path` with a trailing slash removed or added
+fn toggle_trailing_slash(path: &str) -> Option<String> {
+    if !path.starts_with('/') || path == "/" {
+        None
+    } else if path.ends_with('/') {
+        Some(path.trim_end_matches('/').to_owned()).filter(|path| !path.is_empty())
+    } else {
+        Some(format!("{}/", path))
+    }
+}
+
+/// The lowercase name in a `Host` header value, without the port and the trailing dot
+fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        // IPv6 literal, like [::1]:8080
+        host.split(']')
+            .next()
+            .map_or(host.to_owned(), |name| format!("{}]", name))
+    } else {
+        host.split(':').next().unwrap_or(host).to_owned()
+    };
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Methods the app answers for `path`, including the HEAD and OPTIONS it handles on its own
+fn allowed_methods<'a, T: Clone + Sync + Send + Unpin>(
+    app: &'a App<T>,
+    path: &str,
+) -> Vec<&'a str> {
+    let mut allowed_methods = if path == "*" {
+        app.router.methods()
+    } else {
+        app.router.allowed_methods(path)
+    };
+    if allowed_methods.is_empty() {
+        return allowed_methods;
+    }
+
+    if allowed_methods.contains(&"GET") && !allowed_methods.contains(&"HEAD") {
+        allowed_methods.push("HEAD");
+    }
+    if !allowed_methods.contains(&"OPTIONS") {
+        allowed_methods.push("OPTIONS");
+    }
+    allowed_methods.sort();
+    allowed_methods
+}
 
-async fn resolve<T: Clone + Sync + Send + Unpin>(
+/// The path `request` is routed with, or the redirect to the path
+/// with or without a trailing slash
+fn route_path<T: Clone + Sync + Send + Unpin>(
     app: &App<T>,
-    request: Request<T>,
-) -> Result<Response, Box<dyn std::error::Error>> {
-    let method = &request.method;
-    let path = &request.path;
-    let (router, handlers): (&Node<usize>, &Vec<Box<dyn Handler<T>>>) = match method.as_ref() {
-        "GET" => (&app.get_router, &app.get_handlers),
-        "POST" => (&app.post_router, &app.post_handlers),
-        _ => unimplemented!(),
+    request: &Request<T>,
+) -> Result<String, HandlerForRedirect> {
+    // `*` is the target of `OPTIONS *`, not a path
+    let raw_path = if app.normalize_paths && request.path != "*" {
+        normalize_path(&request.path)
+    } else {
+        request.path.clone()
+    };
+    let path = routed_path(&raw_path);
+
+    if app.trailing_slash != TrailingSlash::Strict && app.router.allowed_methods(&path).is_empty() {
+        if let Some(alternate) = toggle_trailing_slash(&raw_path) {
+            let routed = routed_path(&alternate);
+            if !app.router.allowed_methods(&routed).is_empty() {
+                if let TrailingSlash::Redirect(status_code) = app.trailing_slash {
+                    let mut location = alternate.clone();
+                    if !request.query_string.is_empty() {
+                        location = format!("{}?{}", location, request.query_string);
+                    }
+                    return Err(HandlerForRedirect {
+                        status_code,
+                        location,
+                    });
+                }
+                return Ok(routed);
+            }
+        }
+    }
+    Ok(path)
+}
+
+fn find_route<'a, T: Clone + Sync + Send + Unpin>(
+    app: &'a App<T>,
+    method: &str,
+    path: &str,
+) -> Option<Found<'a, Scoped<T>>> {
+    app.router.find(method, path).or_else(|| {
+        // HEAD is answered by the GET handler: the codec drops the body
+        if method == "HEAD" {
+            app.router.find("GET", path)
+        } else {
+            None
+        }
+    })
+}
+
+/// The response to `request`, or a 500 when the one given by the app can't be sent
+async fn resolve<T: Clone + Sync + Send + Unpin>(app: &App<T>, request: Request<T>) -> Response {
+    let logger = request.logger.clone();
+    let is_head_request = request.method == "HEAD";
+
+    let response = dispatch(app, request).await;
+    match check_response(&response, is_head_request) {
+        Ok(()) => response,
+        Err(error) => {
+            error!(logger, "Invalid response"; "error" => error.to_string());
+            error_500("Invalid response")(error).into_response()
+        }
+    }
+}
+
+async fn dispatch<T: Clone + Sync + Send + Unpin>(
+    app: &App<T>,
+    mut request: Request<T>,
+) -> Response {
+    let app = app.for_host(&request);
+
+    let path = match route_path(app, &request) {
+        Ok(path) => path,
+        Err(redirect) => {
+            let redirect = Box::new(redirect).into_endpoint();
+            return Next::new(&app.middlewares, &[], &redirect)
+                .run(request)
+                .await;
+        }
     };
 
-    let path = percent_decode_str(path).decode_utf8_lossy();
-    let state_found = find(router, &path);
+    let method = &request.method;
+    let found = find_route(app, method, &path);
 
-    let func = match state_found.value {
-        None => &app.not_found,
-        Some(f) => handlers.get(*f).unwrap(),
+    let mut allowed_methods = vec![];
+    let (func, scoped) = match found {
+        Some(found) => {
+            request.params = found.params;
+            request.tail = found.tail;
+            (&found.value.endpoint, &found.value.middlewares[..])
+        }
+        None if !app.router.has_method(method) && !STANDARD_METHODS.contains(&method.as_ref()) => {
+            (&app.not_implemented, &[][..])
+        }
+        None => {
+            allowed_methods = self::allowed_methods(app, &path);
+            if allowed_methods.is_empty() {
+                app.not_found_for(&path)
+            } else if method == "OPTIONS" {
+                (&app.default_options, &[][..])
+            } else {
+                (&app.method_not_allowed, &[][..])
+            }
+        }
     };
 
-    func.invoke(request).or_else(|error: HttpError| {
-        let fallback: Vec<u8> = "Unable to serialize".to_owned().into_bytes();
-        let val: Result<Vec<u8>, _> = serde_json::to_vec(&error);
+    let mut response = Next::new(&app.middlewares, scoped, func).run(request).await;
 
-        let body = if let Ok(v) = val { v } else { fallback };
+    let has_allow = response
+        .headers
+        .keys()
+        .any(|name| name.eq_ignore_ascii_case("allow"));
+    if !allowed_methods.is_empty() && !has_allow {
+        response.set_header("Allow", &allowed_methods.join(", "));
+    }
 
-        Ok::<Response, Box<dyn std::error::Error>>(Response {
-            status_code: error.status_code,
-            content_type: Some("text/html".to_owned()),
-            body,
-            headers: HashMap::new(),
-        })
-    })
+    response
 }
 
 pub fn error_500<E>(s: &'static str) -> impl Fn(E) -> HttpError {
@@ -299,9 +946,30 @@ mod tests {
         fn invoke(&self, _req: Request<T>) -> Result<Response, HttpError> {
             Ok(Response {
                 status_code: 200,
+                reason_phrase: None,
                 content_type: Some("text/html".to_owned()),
                 body: b"MyHandler".to_vec(),
                 headers: HashMap::new(),
+                stream: None,
+            })
+        }
+    }
+
+    #[derive(Clone)]
+    struct MyAsyncHandler {}
+    impl<T: Clone + Sync + Send> AsyncHandler<T> for MyAsyncHandler {
+        fn invoke(&self, req: Request<T>) -> HandlerFuture {
+            let name = req.param("name").map(str::to_owned);
+            Box::pin(async move {
+                let name = futures::future::ready(name).await;
+                Ok(Response {
+                    status_code: 200,
+                    reason_phrase: None,
+                    content_type: Some("text/html".to_owned()),
+                    body: format!("MyAsyncHandler {:?}", name).into_bytes(),
+                    headers: HashMap::new(),
+                    stream: None,
+                })
             })
         }
     }
@@ -326,6 +994,1185 @@ mod tests {
         assert_eq!(response.status_code, 404);
     }
 
+    #[test]
+    fn dispatch_methods() {
+        let mut app = get_app();
+        app.post("/", Box::new(MyHandler {}));
+        app.put("/", Box::new(MyHandler {}));
+        app.delete("/", Box::new(MyHandler {}));
+        app.patch("/", Box::new(MyHandler {}));
+        app.head("/", Box::new(MyHandler {}));
+        app.options("/", Box::new(MyHandler {}));
+        app.route("PURGE", "/", Box::new(MyHandler {}));
+
+        for method in &[
+            "GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS", "PURGE",
+        ] {
+            let request = app.create_request(method, "/", "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.status_code, 200);
+
+            let request = app.create_request(method, "/unknwon-path", "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.status_code, 404);
+        }
+    }
+
+    #[test]
+    fn dispatch_unknown_methods() {
+        let app = get_app();
+
+        let request = app.create_request("PUT", "/unknwon-path", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        let request = app.create_request("BREW", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 501);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid HTTP method")]
+    fn route_invalid_method() {
+        let mut app = get_app();
+        app.route("NOT A METHOD", "/", Box::new(MyHandler {}));
+    }
+
+    #[test]
+    fn dispatch_method_not_allowed() {
+        let mut app = get_app();
+
+        let request = app.create_request("POST", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 405);
+        assert_eq!(
+            response.headers["Allow"],
+            vec!["GET, HEAD, OPTIONS".to_owned()]
+        );
+
+        app.put("/", Box::new(MyHandler {}));
+        let request = app.create_request("POST", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 405);
+        assert_eq!(
+            response.headers["Allow"],
+            vec!["GET, HEAD, OPTIONS, PUT".to_owned()]
+        );
+
+        let request = app.create_request("POST", "/unknwon-path", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+        assert!(!response.headers.contains_key("Allow"));
+    }
+
+    #[derive(Clone)]
+    struct MyMethodNotAllowedHandler {}
+    impl<T: Clone + Sync + Send> Handler<T> for MyMethodNotAllowedHandler {
+        fn invoke(&self, _req: Request<T>) -> Result<Response, HttpError> {
+            Err(HttpError {
+                status_code: 405,
+                error_message: "Method not allowed".to_owned(),
+                details: "".to_owned(),
+            })
+        }
+    }
+
+    #[test]
+    fn dispatch_custom_method_not_allowed() {
+        let mut app = get_app();
+        app.set_method_not_allowed(Box::new(MyMethodNotAllowedHandler {}));
+
+        let request = app.create_request("DELETE", "/the name/foo", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 405);
+        assert_eq!(
+            response.headers["Allow"],
+            vec!["GET, HEAD, OPTIONS".to_owned()]
+        );
+        assert_eq!(
+            response.body,
+            br#"{"status_code":405,"error_message":"Method not allowed","details":""}"#.to_vec()
+        );
+    }
+
+    #[test]
+    fn dispatch_automatic_head() {
+        let mut app = get_app();
+        app.post("/only-post", Box::new(MyHandler {}));
+
+        let request = app.create_request("HEAD", "/the name/foo", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        // the body is dropped by HttpCodec, which still sends its Content-Length
+        assert_eq!(response.body, b"MyHandler");
+
+        let request = app.create_request("HEAD", "/only-post", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 405);
+        assert_eq!(response.headers["Allow"], vec!["OPTIONS, POST".to_owned()]);
+    }
+
+    #[test]
+    fn dispatch_automatic_options() {
+        let mut app = get_app();
+        app.post("/", Box::new(MyHandler {}));
+
+        let request = app.create_request("OPTIONS", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 204);
+        assert_eq!(
+            response.headers["Allow"],
+            vec!["GET, HEAD, OPTIONS, POST".to_owned()]
+        );
+
+        let request = app.create_request("OPTIONS", "*", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 204);
+        assert_eq!(
+            response.headers["Allow"],
+            vec!["GET, HEAD, OPTIONS, POST".to_owned()]
+        );
+
+        let request = app.create_request("OPTIONS", "/unknwon-path", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        app.options("/", Box::new(MyHandler {}));
+        let request = app.create_request("OPTIONS", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"MyHandler");
+    }
+
+    #[derive(Deserialize)]
+    struct UserParams {
+        id: u32,
+    }
+
+    #[derive(Clone)]
+    struct ParamsHandler {}
+    impl<T: Clone + Sync + Send> Handler<T> for ParamsHandler {
+        fn invoke(&self, req: Request<T>) -> Result<Response, HttpError> {
+            let params: UserParams = req
+                .params_as()
+                .map_err(error_400("Unable to deserialize params"))?;
+            Ok(Response {
+                status_code: 200,
+                reason_phrase: None,
+                content_type: Some("text/html".to_owned()),
+                body: format!("{} {}", params.id, req.param("title").unwrap()).into_bytes(),
+                headers: HashMap::new(),
+                stream: None,
+            })
+        }
+    }
+
+    #[test]
+    fn dispatch_params() {
+        let mut app = get_app();
+        app.get("/users/:id/posts/:title", Box::new(ParamsHandler {}));
+
+        let request = app.create_request("GET", "/users/42/posts/Hello%20World", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"42 Hello World");
+
+        let request = app.create_request("GET", "/users/foo/posts/bar", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[derive(Clone)]
+    struct TailHandler {}
+    impl<T: Clone + Sync + Send> Handler<T> for TailHandler {
+        fn invoke(&self, req: Request<T>) -> Result<Response, HttpError> {
+            Ok(Response {
+                status_code: 200,
+                reason_phrase: None,
+                content_type: Some("text/html".to_owned()),
+                body: format!("{:?} {:?}", req.param("id"), req.tail).into_bytes(),
+                headers: HashMap::new(),
+                stream: None,
+            })
+        }
+    }
+
+    #[test]
+    fn dispatch_catch_all() {
+        let mut app = get_app();
+        app.get("/static/*path", Box::new(TailHandler {}));
+        app.get("/static/index.html", Box::new(MyHandler {}));
+        app.get("/users/:id/files/*", Box::new(TailHandler {}));
+
+        let request = app.create_request("GET", "/static/css/app%20v2.css", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, br#"None Some("css/app v2.css")"#);
+
+        let request = app.create_request("GET", "/static/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, br#"None Some("")"#);
+
+        let request = app.create_request("GET", "/static/index.html", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"MyHandler");
+
+        let request = app.create_request("GET", "/static", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        let request = app.create_request("GET", "/users/42/files/a/b", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, br#"Some("42") Some("a/b")"#);
+
+        let request = app.create_request("POST", "/static/app.js", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 405);
+    }
+
+    #[test]
+    fn dispatch_specificity() {
+        let mut app = get_app();
+        app.get("/*path", |_: Request<EmptyState>| "root");
+        app.get("/static/*path", |_: Request<EmptyState>| "static");
+        app.get("/static/:id/*path", Box::new(TailHandler {}));
+        app.get("/files/:name/*path", |_: Request<EmptyState>| "name");
+        app.get("/files/:year<uint>/*path", |_: Request<EmptyState>| "year");
+        app.get("/users/:id", |_: Request<EmptyState>| "user");
+        app.get("/:a/:b", |_: Request<EmptyState>| "two params");
+        app.get("/:section(\\w+)/:id", |_: Request<EmptyState>| "section");
+
+        for (path, body) in &[
+            ("/a/b/c", "root"),
+            ("/a/b", "section"),
+            ("/a-b/c", "two params"),
+            ("/users/5", "user"),
+            ("/static/x", "static"),
+            ("/static/", "static"),
+            ("/static/app.js", "static"),
+            ("/static/42/a/b", r#"Some("42") Some("a/b")"#),
+            ("/files/2019/a.txt", "year"),
+            ("/files/docs/a.txt", "name"),
+        ] {
+            let request = app.create_request("GET", path, "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.body, body.as_bytes().to_vec());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "The catch-all segment must be the last one")]
+    fn catch_all_not_last() {
+        let mut app = get_app();
+        app.get("/static/*path/foo", Box::new(TailHandler {}));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Route GET "/the name/:id" conflicts with the already registered "/the name/:name""#
+    )]
+    fn route_conflict() {
+        let mut app = get_app();
+        app.get("/the name/:id", Box::new(MyHandler {}));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Route GET "/api/users" conflicts"#)]
+    fn route_conflict_in_scope() {
+        let mut app = get_app();
+        app.get("/api/users", Box::new(MyHandler {}));
+        app.scope("/api", |scope| {
+            scope.get("/users", Box::new(MyHandler {}));
+        });
+    }
+
+    #[test]
+    fn routes() {
+        let mut app = get_app();
+        app.post("/the name/:name", Box::new(MyHandler {}));
+        app.get("/the name/other", Box::new(MyHandler {}));
+        app.get("/static/*path", Box::new(TailHandler {}));
+        app.get("/static/:a/*path", Box::new(TailHandler {}));
+
+        let routes: Vec<(&str, &str, &str)> = app.routes().collect();
+        assert_eq!(
+            routes,
+            vec![
+                ("GET", "/", "bravery::tests::MyHandler"),
+                ("GET", "/the name/:name", "bravery::tests::MyHandler"),
+                ("POST", "/the name/:name", "bravery::tests::MyHandler"),
+                ("GET", "/the name/other", "bravery::tests::MyHandler"),
+                ("GET", "/static/*path", "bravery::tests::TailHandler"),
+                ("GET", "/static/:a/*path", "bravery::tests::TailHandler"),
+            ]
+        );
+    }
+
+    #[test]
+    fn url_for() {
+        let mut app = get_app();
+        app.get_named("user", "/users/:id", |req: Request<EmptyState>| {
+            req.param("id").unwrap().to_owned()
+        });
+        app.route_named("file", "PUT", "/the files/*path", Box::new(TailHandler {}));
+        app.scope("/api", |scope| {
+            scope.get_named("api_user", "/users/:id", Box::new(MyHandler {}));
+        });
+        let mut admin = App::default();
+        admin.get_named("admin_index", "/", Box::new(MyHandler {}));
+        app.mount("/admin", admin);
+
+        assert_eq!(
+            app.url_for("user", &[("id", "42")]),
+            Ok("/users/42".to_owned())
+        );
+        assert_eq!(
+            app.url_for("user", &[("id", "a b/c?d"), ("unused", "1")]),
+            Ok("/users/a%20b%2Fc%3Fd".to_owned())
+        );
+        assert_eq!(
+            app.url_for("file", &[("path", "docs/read me.md")]),
+            Ok("/the%20files/docs/read%20me.md".to_owned())
+        );
+        assert_eq!(
+            app.url_for("api_user", &[("id", "7")]),
+            Ok("/api/users/7".to_owned())
+        );
+        assert_eq!(app.url_for("admin_index", &[]), Ok("/admin".to_owned()));
+        assert_eq!(
+            app.url_for("user", &[]),
+            Err(UrlForError::MissingParam("id".to_owned()))
+        );
+        assert_eq!(
+            app.url_for("nope", &[]),
+            Err(UrlForError::UnknownRoute("nope".to_owned()))
+        );
+
+        for id in &["a b", "a b/c?d", "100%2F", "/"] {
+            let path = app.url_for("user", &[("id", id)]).unwrap();
+            let request = app.create_request("GET", &path, "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.status_code, 200);
+            assert_eq!(response.body, id.as_bytes().to_vec());
+        }
+
+        let path = app.url_for("file", &[("path", "docs/read me.md")]).unwrap();
+        let request = app.create_request("PUT", &path, "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, br#"None Some("docs/read me.md")"#);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Route name "user" is already used by "/users/:id""#)]
+    fn url_for_duplicate_name() {
+        let mut app = get_app();
+        app.get_named("user", "/users/:id", Box::new(MyHandler {}));
+        app.get_named("user", "/people/:id", Box::new(MyHandler {}));
+    }
+
+    #[test]
+    fn dispatch_constraints() {
+        let mut app = get_app();
+        app.get("/users/:id(\\d+)", |req: Request<EmptyState>| {
+            format!("id {}", req.param("id").unwrap())
+        });
+        app.get("/users/:token<uuid>", |req: Request<EmptyState>| {
+            format!("token {}", req.param("token").unwrap())
+        });
+        app.get("/users/:name", |req: Request<EmptyState>| {
+            format!("name {}", req.param("name").unwrap())
+        });
+        app.get("/users/me", |_: Request<EmptyState>| "me");
+        app.get("/:section(\\w+)/:id", |req: Request<EmptyState>| {
+            format!("section {}", req.param("section").unwrap())
+        });
+        app.get("/files/:year<uint>/*path", |req: Request<EmptyState>| {
+            format!(
+                "{} {}",
+                req.param("year").unwrap(),
+                req.tail.as_ref().unwrap()
+            )
+        });
+
+        for (path, body) in &[
+            ("/users/42", "id 42"),
+            (
+                "/users/67e55044-10b1-426f-9247-bb680e5fe0c8",
+                "token 67e55044-10b1-426f-9247-bb680e5fe0c8",
+            ),
+            ("/users/42a", "name 42a"),
+            ("/users/me", "me"),
+            ("/posts/7", "section posts"),
+            ("/files/2019/a/b.txt", "2019 a/b.txt"),
+        ] {
+            let request = app.create_request("GET", path, "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.body, body.as_bytes().to_vec());
+        }
+
+        let request = app.create_request("GET", "/files/last/a.txt", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        app.get_named("user", "/accounts/:id<int>", Box::new(MyHandler {}));
+        assert_eq!(
+            app.url_for("user", &[("id", "-3")]),
+            Ok("/accounts/-3".to_owned())
+        );
+        assert_eq!(
+            app.url_for("user", &[("id", "me")]),
+            Err(UrlForError::InvalidParam("id".to_owned()))
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Invalid constraint in "/users/:id<number>": unknown type "number""#
+    )]
+    fn route_unknown_constraint() {
+        let mut app = get_app();
+        app.get("/users/:id<number>", Box::new(MyHandler {}));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Invalid constraint in "/users/:id([0-9)""#)]
+    fn route_invalid_constraint() {
+        let mut app = get_app();
+        app.get("/users/:id([0-9)", Box::new(MyHandler {}));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Route GET "/users/:name(\\d+)" conflicts"#)]
+    fn route_constraint_conflict() {
+        let mut app = get_app();
+        app.get("/users/:id(\\d+)", Box::new(MyHandler {}));
+        app.get("/users/:name(\\d+)", Box::new(MyHandler {}));
+    }
+
+    #[test]
+    #[should_panic(expected = "conflicts with the already registered")]
+    fn route_equivalent_constraint_conflict() {
+        let mut app = get_app();
+        app.get("/users/:id(\\d+)", Box::new(MyHandler {}));
+        app.get("/users/:n<uint>", Box::new(MyHandler {}));
+    }
+
+    #[test]
+    fn dispatch_hosts() {
+        let mut blog = App::default();
+        blog.get("/", |_: Request<EmptyState>| "blog");
+        let mut tenants = App::default();
+        tenants.get("/", |_: Request<EmptyState>| "tenant");
+        let mut admin = App::default();
+        admin.get("/", |_: Request<EmptyState>| "admin");
+
+        let mut app = get_app();
+        app.host("blog.example.test", blog);
+        app.host("*.example.test", tenants);
+        app.host("*.admin.example.test", admin);
+
+        for (host, body) in &[
+            (Some("blog.example.test"), "blog"),
+            (Some("Blog.Example.Test:8080"), "blog"),
+            (Some("blog.example.test."), "blog"),
+            (Some("acme.example.test"), "tenant"),
+            (Some("a.b.example.test"), "tenant"),
+            (Some("root.admin.example.test"), "admin"),
+            (Some("example.test"), "MyHandler"),
+            (Some("[::1]:8080"), "MyHandler"),
+            (None, "MyHandler"),
+        ] {
+            let mut request = app.create_request("GET", "/", "", b"".to_vec());
+            if let Some(host) = host {
+                request.headers.insert("host".to_owned(), host.to_string());
+            }
+            let response = app.inject(request);
+            assert_eq!(response.body, body.as_bytes().to_vec());
+        }
+
+        let mut request = app.create_request("GET", "/the name/foo", "", b"".to_vec());
+        request
+            .headers
+            .insert("host".to_owned(), "blog.example.test".to_owned());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Host "blog.example.test" is already registered"#)]
+    fn host_already_registered() {
+        let mut app = get_app();
+        app.host("blog.example.test", App::default());
+        app.host("Blog.example.test", App::default());
+    }
+
+    #[test]
+    fn dispatch_trailing_slash() {
+        let mut app = get_app();
+        app.get("/users", |_: Request<EmptyState>| "users");
+        app.get("/users/:id/", |req: Request<EmptyState>| {
+            format!("user {}", req.param("id").unwrap())
+        });
+
+        let request = app.create_request("GET", "/users/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        app.set_trailing_slash(TrailingSlash::Redirect(308));
+        let request = app.create_request("GET", "/users/", "page=2", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 308);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some(&vec!["/users?page=2".to_owned()])
+        );
+
+        let request = app.create_request("POST", "/users/42", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 308);
+        assert_eq!(
+            response.headers.get("Location"),
+            Some(&vec!["/users/42/".to_owned()])
+        );
+
+        let request = app.create_request("GET", "/unknwon-path/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        app.set_trailing_slash(TrailingSlash::Match);
+        let request = app.create_request("GET", "/users/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"users".to_vec());
+
+        let request = app.create_request("GET", "/users/42", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"user 42".to_vec());
+
+        let request = app.create_request("POST", "/users/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 405);
+    }
+
+    #[test]
+    fn dispatch_normalized_paths() {
+        let mut app = get_app();
+        app.get("/users/:id", |req: Request<EmptyState>| {
+            format!("user {}", req.param("id").unwrap())
+        });
+        app.get("/static/*path", Box::new(TailHandler {}));
+
+        let request = app.create_request("GET", "//users/./42", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        app.set_normalize_paths(true);
+        for path in &[
+            "//users/./42",
+            "/users//42",
+            "/other/../users/42",
+            "/../users/42",
+            "/other/%2e%2e/users/42",
+            "/other/.%2E/users/42",
+            "/users/%2e/42",
+        ] {
+            let request = app.create_request("GET", path, "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.body, b"user 42".to_vec());
+        }
+
+        let request = app.create_request("GET", "/static/%2e%2e/%2e%2e/etc/passwd", "", vec![]);
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        let request = app.create_request("OPTIONS", "*", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 204);
+        assert_eq!(
+            response.headers["Allow"],
+            vec!["GET, HEAD, OPTIONS".to_owned()]
+        );
+
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path("/a//b/"), "/a/b/");
+        assert_eq!(normalize_path("/.."), "/");
+        assert_eq!(normalize_path("/a/b/%2E%2e"), "/a/");
+    }
+
+    #[test]
+    fn dispatch_case_insensitive() {
+        let mut app = get_app();
+        app.get("/Users/:Name", |req: Request<EmptyState>| {
+            format!("user {}", req.param("Name").unwrap())
+        });
+        app.get("/files/:id(\\d+)/Raw", |req: Request<EmptyState>| {
+            format!("file {}", req.param("id").unwrap())
+        });
+
+        let request = app.create_request("GET", "/users/Tommaso", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+
+        app.set_case_insensitive(true);
+        for (path, body) in &[
+            ("/users/Tommaso", "user Tommaso"),
+            ("/USERS/Tommaso", "user Tommaso"),
+            ("/Users/Tommaso", "user Tommaso"),
+            ("/FILES/3/raw", "file 3"),
+        ] {
+            let request = app.create_request("GET", path, "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.body, body.as_bytes().to_vec());
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Route GET "/users" conflicts with the already registered "/Users""#
+    )]
+    fn case_insensitive_conflict() {
+        let mut app = get_app();
+        app.get("/Users", Box::new(MyHandler {}));
+        app.get("/users", Box::new(MyHandler {}));
+        app.set_case_insensitive(true);
+    }
+
+    #[derive(Clone)]
+    struct JsonNotFoundHandler {}
+    impl<T: Clone + Sync + Send> Handler<T> for JsonNotFoundHandler {
+        fn invoke(&self, req: Request<T>) -> Result<Response, HttpError> {
+            Err(HttpError {
+                status_code: 404,
+                error_message: "Not found".to_owned(),
+                details: req.path,
+            })
+        }
+    }
+
+    #[test]
+    fn dispatch_custom_not_found() {
+        let mut app = get_app();
+        app.set_not_found(Box::new(MyHandler {}));
+        app.set_not_found_for("/api/", Box::new(JsonNotFoundHandler {}));
+        app.set_not_found_for("/api/v2", Box::new(TailHandler {}));
+
+        let request = app.create_request("GET", "/unknwon-path", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"MyHandler");
+
+        let request = app.create_request("GET", "/apis", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"MyHandler");
+
+        for path in &["/api", "/api/", "/api/users/42"] {
+            let request = app.create_request("GET", path, "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.status_code, 404);
+            assert_eq!(
+                response.body,
+                format!(
+                    r#"{{"status_code":404,"error_message":"Not found","details":"{}"}}"#,
+                    path
+                )
+                .into_bytes()
+            );
+        }
+
+        let request = app.create_request("GET", "/api/v2/users", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"None None");
+    }
+
+    #[test]
+    fn dispatch_async() {
+        let mut app = get_app();
+        app.get("/async/:name", Box::new(MyAsyncHandler {}));
+        app.set_not_found_for("/async", Box::new(MyAsyncHandler {}));
+
+        let request = app.create_request("GET", "/async/bravery", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"MyAsyncHandler Some(\"bravery\")".to_vec());
+
+        let request = app.create_request("HEAD", "/async/bravery", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+
+        let request = app.create_request("GET", "/async/a/b", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"MyAsyncHandler None".to_vec());
+
+        let request = app.create_request("GET", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"MyHandler");
+    }
+
+    #[test]
+    fn dispatch_closures() {
+        let mut app = get_app();
+        let greeting = "Hello".to_owned();
+        app.get("/hello/:name", move |req: Request<EmptyState>| Response {
+            status_code: 200,
+            reason_phrase: None,
+            content_type: Some("text/html".to_owned()),
+            body: format!("{} {}", greeting, req.param("name").unwrap_or_default()).into_bytes(),
+            headers: HashMap::new(),
+            stream: None,
+        });
+        app.post("/echo", |req: Request<EmptyState>| {
+            async move { (201, req.body) }
+        });
+
+        let request = app.create_request("GET", "/hello/bravery", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"Hello bravery".to_vec());
+
+        let request = app.create_request("POST", "/echo", "", b"ping".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.body, b"ping".to_vec());
+    }
+
+    #[derive(Deserialize)]
+    struct Pagination {
+        page: u32,
+    }
+    #[derive(Deserialize)]
+    struct Body {
+        name: String,
+    }
+    struct Authorization;
+    impl HeaderName for Authorization {
+        const NAME: &'static str = "authorization";
+    }
+
+    fn text(status_code: u16, body: String) -> Result<Response, HttpError> {
+        Ok(Response {
+            status_code,
+            reason_phrase: None,
+            content_type: Some("text/html".to_owned()),
+            body: body.into_bytes(),
+            headers: HashMap::new(),
+            stream: None,
+        })
+    }
+
+    #[test]
+    fn dispatch_extractors() {
+        let mut app = App::new_with_state(42u32);
+        app.get(
+            "/users/:id",
+            |Path(params): Path<UserParams>, Query(pagination): Query<Pagination>| {
+                text(200, format!("{} {}", params.id, pagination.page))
+            },
+        );
+        app.post(
+            "/users",
+            |Json(body): Json<Body>, token: Header<Authorization>, State(state): State<u32>| {
+                async move { text(201, format!("{} {} {}", body.name, token.value, state)) }
+            },
+        );
+
+        let request = app.create_request("GET", "/users/7", "page=3", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"7 3".to_vec());
+
+        let request = app.create_request("GET", "/users/seven", "page=3", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 400);
+
+        let request = app.create_request("GET", "/users/7", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 400);
+
+        let mut request = app.create_request("POST", "/users", "", br#"{"name":"foo"}"#.to_vec());
+        request
+            .headers
+            .insert("authorization".to_owned(), "secret".to_owned());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.body, b"foo secret 42".to_vec());
+
+        let request = app.create_request("POST", "/users", "", br#"{"name":"foo"}"#.to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 400);
+        assert_eq!(
+            response.body,
+            br#"{"status_code":400,"error_message":"Missing header","details":"authorization"}"#
+                .to_vec()
+        );
+
+        let mut request = app.create_request("POST", "/users", "", b"{".to_vec());
+        request
+            .headers
+            .insert("authorization".to_owned(), "secret".to_owned());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[derive(Serialize)]
+    struct Greeting {
+        message: &'static str,
+    }
+
+    #[test]
+    fn dispatch_invalid_response() {
+        let mut app = App::default();
+        app.get("/status", |_req: Request<EmptyState>| (600, "x"));
+        app.get("/content-type", |_req: Request<EmptyState>| {
+            let mut response = "x".into_response();
+            response.set_header("Content-Type", "text/csv");
+            response
+        });
+        app.get("/injection", |_req: Request<EmptyState>| {
+            let mut response = "x".into_response();
+            response.set_header("X-Name", "a\r\nSet-Cookie: b");
+            response
+        });
+        app.get("/content-length", |_req: Request<EmptyState>| {
+            let mut response = "x".into_response();
+            response.set_header("Content-Length", "2");
+            response
+        });
+
+        for path in &["/status", "/content-type", "/injection", "/content-length"] {
+            let request = app.create_request("GET", path, "", b"".to_vec());
+            let response = app.inject(request);
+            assert_eq!(response.status_code, 500, "{}", path);
+            assert_eq!(
+                response.body,
+                br#"{"status_code":500,"error_message":"Invalid response","details":""}"#.to_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn dispatch_head_content_length() {
+        let mut app = App::default();
+        app.head("/file", |_req: Request<EmptyState>| {
+            let mut response = Vec::new().into_response();
+            response.set_header("Content-Length", "1234");
+            response
+        });
+
+        let request = app.create_request("HEAD", "/file", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.headers["Content-Length"], vec!["1234".to_owned()]);
+    }
+
+    #[test]
+    fn dispatch_into_response() {
+        let mut app = get_app();
+        app.get("/str", |_: Request<EmptyState>| "Hello");
+        app.get("/string/:id", |Path(params): Path<UserParams>| {
+            format!("{}", params.id)
+        });
+        app.get("/bytes", |_: Request<EmptyState>| (202, vec![0u8, 159]));
+        app.get("/json", |_: Request<EmptyState>| {
+            async {
+                Json(Greeting {
+                    message: "Hello, World!",
+                })
+            }
+        });
+        app.get("/result", |Query(pagination): Query<Pagination>| {
+            if pagination.page == 0 {
+                Err(HttpError {
+                    status_code: 422,
+                    error_message: "Invalid page".to_owned(),
+                    details: "".to_owned(),
+                })
+            } else {
+                Ok((201, "created"))
+            }
+        });
+
+        let request = app.create_request("GET", "/str", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            response.content_type,
+            Some("text/plain; charset=utf-8".to_owned())
+        );
+        assert_eq!(response.body, b"Hello".to_vec());
+
+        let request = app.create_request("GET", "/string/42", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"42".to_vec());
+
+        let request = app.create_request("GET", "/bytes", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 202);
+        assert_eq!(
+            response.content_type,
+            Some("application/octet-stream".to_owned())
+        );
+        assert_eq!(response.body, vec![0u8, 159]);
+
+        let request = app.create_request("GET", "/json", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.content_type, Some("application/json".to_owned()));
+        assert_eq!(response.body, br#"{"message":"Hello, World!"}"#.to_vec());
+
+        let request = app.create_request("GET", "/result", "page=1", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.body, b"created".to_vec());
+
+        let request = app.create_request("GET", "/result", "page=0", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 422);
+        assert_eq!(
+            response.body,
+            br#"{"status_code":422,"error_message":"Invalid page","details":""}"#.to_vec()
+        );
+    }
+
+    #[test]
+    fn dispatch_streamed() {
+        let mut app = App::default();
+        app.get("/export", |_req: Request<EmptyState>| {
+            let rows = (1..=3).map(|id| Ok(format!("{},user {}\n", id, id).into_bytes()));
+            (
+                201,
+                Response::streamed("text/csv", futures::stream::iter(rows)),
+            )
+        });
+
+        let request = app.create_request("GET", "/export", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.content_type, Some("text/csv".to_owned()));
+        assert_eq!(response.body, b"".to_vec());
+
+        let chunks: Vec<Vec<u8>> = block_on(response.stream.unwrap().collect::<Vec<_>>())
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(chunks.concat(), b"1,user 1\n2,user 2\n3,user 3\n".to_vec());
+    }
+
+    #[test]
+    fn dispatch_streamed_body() {
+        let mut app = App::default();
+        app.post(
+            "/upload",
+            Streamed(|mut req: Request<EmptyState>| {
+                async move {
+                    let mut stream = req.stream.take().unwrap();
+                    let mut size = 0;
+                    while let Some(chunk) = stream.next().await {
+                        size += chunk.map_err(error_400("Unable to read the body"))?.len();
+                    }
+                    Ok::<_, HttpError>(format!("{} bytes", size))
+                }
+            }),
+        );
+        app.post("/buffered", |req: Request<EmptyState>| req.body);
+
+        let upload = app.create_request("POST", "/upload", "", vec![]);
+        assert!(app.streams_body(&upload));
+        let buffered = app.create_request("POST", "/buffered", "", vec![]);
+        assert!(!app.streams_body(&buffered));
+        let missing = app.create_request("POST", "/missing", "", vec![]);
+        assert!(!app.streams_body(&missing));
+
+        let mut request = upload;
+        let chunks = vec![Ok(vec![0u8; 1000]), Ok(vec![1u8; 24])];
+        request.stream = Some(Box::pin(futures::stream::iter(chunks)));
+        let response = app.inject(request);
+        assert_eq!(response.body, b"1024 bytes".to_vec());
+
+        let mut request = app.create_request("POST", "/upload", "", vec![]);
+        let chunks = vec![Ok(vec![0u8; 10]), Err(io::ErrorKind::UnexpectedEof.into())];
+        request.stream = Some(Box::pin(futures::stream::iter(chunks)));
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 400);
+    }
+
+    fn trace_outer<'a>(
+        req: Request<EmptyState>,
+        next: Next<'a, EmptyState>,
+    ) -> MiddlewareFuture<'a> {
+        Box::pin(async move {
+            let mut response = next.run(req).await;
+            response.add_header("X-Trace", "outer");
+            response
+        })
+    }
+
+    fn trace_inner<'a>(
+        req: Request<EmptyState>,
+        next: Next<'a, EmptyState>,
+    ) -> MiddlewareFuture<'a> {
+        Box::pin(async move {
+            let mut response = next.run(req).await;
+            response.add_header("X-Trace", "inner");
+            response
+        })
+    }
+
+    #[derive(Clone)]
+    struct RequireToken {
+        token: String,
+    }
+    impl Middleware<EmptyState> for RequireToken {
+        fn call<'a>(
+            &'a self,
+            req: Request<EmptyState>,
+            next: Next<'a, EmptyState>,
+        ) -> MiddlewareFuture<'a> {
+            if req.headers.get("authorization") == Some(&self.token) {
+                next.run(req)
+            } else {
+                Box::pin(async { (401, "Unauthorized").into_response() })
+            }
+        }
+    }
+
+    #[test]
+    fn dispatch_middlewares() {
+        let mut app = get_app();
+        app.wrap(trace_outer);
+        app.wrap(trace_inner);
+        app.wrap(RequireToken {
+            token: "secret".to_owned(),
+        });
+
+        let mut request = app.create_request("GET", "/", "", b"".to_vec());
+        request
+            .headers
+            .insert("authorization".to_owned(), "secret".to_owned());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, b"MyHandler");
+        assert_eq!(
+            response.headers.get("X-Trace"),
+            Some(&vec!["inner".to_owned(), "outer".to_owned()])
+        );
+
+        let request = app.create_request("GET", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 401);
+        assert_eq!(response.body, b"Unauthorized");
+        assert_eq!(
+            response.headers.get("X-Trace"),
+            Some(&vec!["inner".to_owned(), "outer".to_owned()])
+        );
+
+        let mut request = app.create_request("GET", "/unknwon-path", "", b"".to_vec());
+        request
+            .headers
+            .insert("authorization".to_owned(), "secret".to_owned());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 404);
+        assert_eq!(
+            response.headers.get("X-Trace"),
+            Some(&vec!["inner".to_owned(), "outer".to_owned()])
+        );
+    }
+
+    #[test]
+    fn dispatch_scopes() {
+        let mut app = get_app();
+        app.wrap(trace_outer);
+        app.scope("/api/v1", |scope| {
+            scope.wrap(trace_inner);
+            scope.get("/", |_: Request<EmptyState>| "index");
+            scope.get("/users/:id", |Path(params): Path<UserParams>| {
+                format!("user {}", params.id)
+            });
+            scope.scope("/admin/", |admin| {
+                admin.wrap(RequireToken {
+                    token: "secret".to_owned(),
+                });
+                admin.post("/users", |_: Request<EmptyState>| (201, "created"));
+                admin.set_not_found(|_: Request<EmptyState>| (404, "admin not found"));
+            });
+        });
+
+        let request = app.create_request("GET", "/api/v1", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"index".to_vec());
+
+        let request = app.create_request("GET", "/api/v1/users/3", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"user 3".to_vec());
+        assert_eq!(
+            response.headers.get("X-Trace"),
+            Some(&vec!["inner".to_owned(), "outer".to_owned()])
+        );
+
+        let request = app.create_request("POST", "/api/v1/admin/users", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 401);
+
+        let mut request = app.create_request("POST", "/api/v1/admin/users", "", b"".to_vec());
+        request
+            .headers
+            .insert("authorization".to_owned(), "secret".to_owned());
+        let response = app.inject(request);
+        assert_eq!(response.status_code, 201);
+
+        let mut request = app.create_request("GET", "/api/v1/admin/unknwon", "", b"".to_vec());
+        request
+            .headers
+            .insert("authorization".to_owned(), "secret".to_owned());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"admin not found".to_vec());
+
+        let request = app.create_request("GET", "/unknwon-path", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"404 Handler".to_vec());
+        assert_eq!(
+            response.headers.get("X-Trace"),
+            Some(&vec!["outer".to_owned()])
+        );
+    }
+
+    #[test]
+    fn dispatch_mount() {
+        let mut admin = App::default();
+        admin.wrap(trace_inner);
+        admin.get("/", |_: Request<EmptyState>| "admin");
+        admin.get("/users/:id", |Path(params): Path<UserParams>| {
+            format!("user {}", params.id)
+        });
+        admin.set_not_found(|_: Request<EmptyState>| (404, "admin not found"));
+
+        let mut app = get_app();
+        app.mount("/admin", admin);
+
+        let request = app.create_request("GET", "/admin", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"admin".to_vec());
+
+        let request = app.create_request("GET", "/admin/users/9", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"user 9".to_vec());
+        assert_eq!(
+            response.headers.get("X-Trace"),
+            Some(&vec!["inner".to_owned()])
+        );
+
+        let request = app.create_request("GET", "/admin/unknwon", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"admin not found".to_vec());
+
+        let request = app.create_request("GET", "/", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"MyHandler".to_vec());
+        assert_eq!(response.headers.get("X-Trace"), None);
+
+        let request = app.create_request("GET", "/unknwon-path", "", b"".to_vec());
+        let response = app.inject(request);
+        assert_eq!(response.body, b"404 Handler".to_vec());
+    }
+
     #[test]
     fn encoded() {
         let app = get_app();
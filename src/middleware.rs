@@ -0,0 +1,70 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::handler::Endpoint;
+use crate::request::Request;
+use crate::response::{IntoResponse, Response};
+
+pub type MiddlewareFuture<'a> = Pin<Box<dyn Future<Output = Response> + Send + 'a>>;
+
+/// Runs around the handler: it may answer on its own or call `next.run(req)`
+/// and post-process the `Response` it resolves to
+pub trait Middleware<T: Clone + Send + Sync>: objekt::Clone + Sync + Send {
+    fn call<'a>(&'a self, req: Request<T>, next: Next<'a, T>) -> MiddlewareFuture<'a>;
+}
+objekt::clone_trait_object!(<T: Clone + Send + Sync> Middleware<T>);
+
+impl<T, F> Middleware<T> for F
+where
+    T: Clone + Send + Sync,
+    F: for<'a> Fn(Request<T>, Next<'a, T>) -> MiddlewareFuture<'a> + Clone + Send + Sync,
+{
+    fn call<'a>(&'a self, req: Request<T>, next: Next<'a, T>) -> MiddlewareFuture<'a> {
+        self(req, next)
+    }
+}
+
+/// The rest of the chain: the middlewares still to run, then the handler
+pub struct Next<'a, T: Clone + Send + Sync> {
+    middlewares: &'a [Box<dyn Middleware<T>>],
+    // the middlewares of the scopes of the route, run after the app ones
+    scoped: &'a [Box<dyn Middleware<T>>],
+    endpoint: &'a Endpoint<T>,
+}
+
+impl<'a, T: Clone + Send + Sync> Next<'a, T> {
+    pub(crate) fn new(
+        middlewares: &'a [Box<dyn Middleware<T>>],
+        scoped: &'a [Box<dyn Middleware<T>>],
+        endpoint: &'a Endpoint<T>,
+    ) -> Self {
+        Next {
+            middlewares,
+            scoped,
+            endpoint,
+        }
+    }
+
+    pub fn run(self, req: Request<T>) -> MiddlewareFuture<'a> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware.call(
+                req,
+                Next {
+                    middlewares,
+                    scoped: self.scoped,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None if !self.scoped.is_empty() => Next::new(self.scoped, &[], self.endpoint).run(req),
+            None => {
+                let endpoint = self.endpoint;
+                Box::pin(async move {
+                    endpoint
+                        .call(req)
+                        .await
+                        .unwrap_or_else(IntoResponse::into_response)
+                })
+            }
+        }
+    }
+}
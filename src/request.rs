@@ -1,14 +1,24 @@
 use std::collections::HashMap;
 
+use crate::response::BodyStream;
+
 pub struct Request<C: Clone + Sync + Send> {
     pub method: String,
     pub path: String,
     pub query_string: String,
     pub headers: HashMap<String, String>,
+    /// Fields of the trailer section ending a chunked body, never merged into `headers`
+    pub trailers: HashMap<String, String>,
+    /// Percent-decoded values captured by the `:name` segments of the matched route
+    pub params: HashMap<String, String>,
+    /// What a trailing `*name` segment of the matched route captured, e.g. `css/app.css`
+    pub tail: Option<String>,
     pub content_type: Option<String>,
     pub content_length: usize,
     pub header_lenght: usize,
     pub body: Vec<u8>,
+    /// The body as it arrives, in place of `body`, for the handlers wrapped in `Streamed`
+    pub stream: Option<BodyStream>,
     pub logger: slog::Logger,
     pub context: C,
 }
@@ -27,4 +37,20 @@ impl<C: Clone + Sync + Send> Request<C> {
     {
         serde_urlencoded::from_str(&self.query_string)
     }
+
+    pub fn params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    pub fn params_as<T>(&self) -> Result<T, serde::de::value::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let params = serde_urlencoded::to_string(&self.params).map_err(serde::de::Error::custom)?;
+        serde_urlencoded::from_str(&params)
+    }
 }
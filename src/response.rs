@@ -1,8 +1,133 @@
 use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+
+use futures::stream::Stream;
+use serde::Serialize;
+
+use crate::extract::Json;
+use crate::{error_500, HttpError};
 
 pub struct Response {
     pub status_code: u16,
-    pub headers: HashMap<String, String>,
+    /// Overrides the canonical reason phrase of `status_code` when set
+    pub reason_phrase: Option<String>,
+    /// Every value is written as its own header line, so repeated headers like `Set-Cookie` are kept
+    pub headers: HashMap<String, Vec<String>>,
     pub content_type: Option<String>,
     pub body: Vec<u8>,
+    /// Sent chunk by chunk with `Transfer-Encoding: chunked` in place of `body` when set
+    pub stream: Option<BodyStream>,
+}
+
+/// The chunks of a streamed body: an error aborts the response and closes the connection
+pub type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Vec<u8>>> + Send>>;
+
+impl Response {
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .entry(name.to_owned())
+            .or_default()
+            .push(value.to_owned());
+    }
+
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.insert(name.to_owned(), vec![value.to_owned()]);
+    }
+
+    /// A 200 response whose body is produced by `stream` while it is sent
+    pub fn streamed<S>(content_type: &str, stream: S) -> Response
+    where
+        S: Stream<Item = io::Result<Vec<u8>>> + Send + 'static,
+    {
+        Response {
+            stream: Some(Box::pin(stream)),
+            ..Response::with_body(content_type, vec![])
+        }
+    }
+}
+
+/// What a handler can return: `resolve` turns it into the `Response` sent back
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl Response {
+    fn with_body(content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status_code: 200,
+            reason_phrase: None,
+            headers: HashMap::new(),
+            content_type: Some(content_type.to_owned()),
+            body,
+            stream: None,
+        }
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::with_body("text/plain; charset=utf-8", self.into_bytes())
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Response::with_body("text/plain; charset=utf-8", self.as_bytes().to_vec())
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        Response::with_body("application/octet-stream", self)
+    }
+}
+
+/// Replaces the status code of the wrapped response
+impl<T: IntoResponse> IntoResponse for (u16, T) {
+    fn into_response(self) -> Response {
+        let mut response = self.1.into_response();
+        response.status_code = self.0;
+        response
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => Response::with_body("application/json", body),
+            Err(error) => error_500("Unable to serialize")(error).into_response(),
+        }
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        let body = serde_json::to_vec(&self)
+            .unwrap_or_else(|_| "Unable to serialize".to_owned().into_bytes());
+
+        Response {
+            status_code: self.status_code,
+            reason_phrase: None,
+            headers: HashMap::new(),
+            content_type: Some("text/html".to_owned()),
+            body,
+            stream: None,
+        }
+    }
 }
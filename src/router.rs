@@ -0,0 +1,522 @@
+use bravery_router::{add, create_root_node, find, optimize, Node};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+// What `url_for` escapes in a path segment
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// What a `:name(regex)` or `:name<type>` segment accepts
+#[derive(Clone)]
+struct Constraint {
+    source: String,
+    regex: Regex,
+}
+
+impl Constraint {
+    fn new(source: &str) -> Result<Constraint, String> {
+        Regex::new(&format!("^(?:{})$", source))
+            .map(|regex| Constraint {
+                source: source.to_owned(),
+                regex,
+            })
+            .map_err(|error| error.to_string())
+    }
+
+    fn typed(name: &str) -> Result<Constraint, String> {
+        match name {
+            "int" => Constraint::new("-?[0-9]+"),
+            "uint" => Constraint::new("[0-9]+"),
+            "uuid" => Constraint::new(
+                "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+            ),
+            _ => Err(format!("unknown type {:?}", name)),
+        }
+    }
+
+    /// The source with `\d` spelled `[0-9]`, so `:id(\d+)` and `:id<uint>` are the same constraint
+    fn key(&self) -> String {
+        self.source.replace("\\d", "[0-9]")
+    }
+
+    fn parse(constraint: &str) -> Result<Constraint, String> {
+        let inner = || &constraint[1..constraint.len() - 1];
+        match (constraint.chars().next(), constraint.chars().last()) {
+            (Some('('), Some(')')) if constraint.len() > 1 => Constraint::new(inner()),
+            (Some('<'), Some('>')) if constraint.len() > 1 => Constraint::typed(inner()),
+            _ => Err(format!("unclosed constraint {:?}", constraint)),
+        }
+    }
+}
+
+/// `path` with its segments percent-decoded, but for the `%` and `/` they contain:
+/// the router matches on it, so a `/` in a param is not taken for a separator
+pub(crate) fn routed_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| escape_segment(&percent_decode_str(segment).decode_utf8_lossy()))
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn escape_segment(segment: &str) -> String {
+    segment.replace('%', "%25").replace('/', "%2F")
+}
+
+/// A segment of a routed path, as captured in a param or a tail
+fn unescape_segment(segment: &str) -> String {
+    percent_decode_str(segment).decode_utf8_lossy().into_owned()
+}
+
+fn same_text(a: &str, b: &str, case_insensitive: bool) -> bool {
+    if case_insensitive {
+        a.to_lowercase() == b.to_lowercase()
+    } else {
+        a == b
+    }
+}
+
+#[derive(Clone)]
+enum Segment {
+    Static(String),
+    Param(String, Option<Constraint>),
+    CatchAll(String),
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Result<Segment, String> {
+        let mut chars = segment.chars();
+        match chars.next() {
+            Some(':') => {
+                let spec = chars.as_str();
+                match spec.find(&['(', '<'][..]) {
+                    Some(index) => {
+                        let constraint = Constraint::parse(&spec[index..])?;
+                        Ok(Segment::Param(spec[..index].to_owned(), Some(constraint)))
+                    }
+                    None => Ok(Segment::Param(spec.to_owned(), None)),
+                }
+            }
+            Some('*') => Ok(Segment::CatchAll(chars.as_str().to_owned())),
+            _ => Ok(Segment::Static(segment.to_owned())),
+        }
+    }
+
+    /// Whether some path segment can match both
+    fn overlaps(&self, other: &Segment, case_insensitive: bool) -> bool {
+        match (self, other) {
+            (Segment::Static(a), Segment::Static(b)) => same_text(a, b, case_insensitive),
+            (Segment::Param(_, a), Segment::Param(_, b)) => {
+                a.as_ref().map(Constraint::key) == b.as_ref().map(Constraint::key)
+            }
+            (Segment::CatchAll(_), Segment::CatchAll(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn is_constrained(&self) -> bool {
+        match self {
+            Segment::Param(_, constraint) => constraint.is_some(),
+            _ => false,
+        }
+    }
+
+    // Lower for the segments matching fewer paths
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_, Some(_)) => 1,
+            Segment::Param(_, None) => 2,
+            Segment::CatchAll(_) => 3,
+        }
+    }
+
+    /// Whether `value`, a non-empty path segment, satisfies the constraint of a param
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            Segment::Param(_, constraint) => constraint.iter().all(|c| c.regex.is_match(value)),
+            _ => false,
+        }
+    }
+
+    fn static_text(&self) -> Option<&str> {
+        match self {
+            Segment::Static(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn catch_all_name(&self) -> Option<&str> {
+        match self {
+            Segment::CatchAll(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Route<V: Clone> {
+    method: String,
+    path: String,
+    segments: Vec<Segment>,
+    value: V,
+}
+
+impl<V: Clone> Route<V> {
+    fn is_catch_all(&self) -> bool {
+        self.segments
+            .last()
+            .and_then(Segment::catch_all_name)
+            .is_some()
+    }
+
+    fn is_static(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| segment.static_text().is_some())
+    }
+
+    fn is_constrained(&self) -> bool {
+        self.segments.iter().any(Segment::is_constrained)
+    }
+
+    /// Whether both routes match the same paths, so the later one could never be reached
+    fn conflicts_with(&self, other: &Route<V>, case_insensitive: bool) -> bool {
+        self.method == other.method
+            && self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(&other.segments)
+                .all(|(a, b)| a.overlaps(b, case_insensitive))
+    }
+
+    /// Orders the routes matching a path so that the most specific one wins:
+    /// segment by segment, a static segment first, then a constrained param,
+    /// then any param, then a catch-all
+    fn cmp_specificity(&self, other: &Route<V>) -> Ordering {
+        self.segments
+            .iter()
+            .zip(&other.segments)
+            .map(|(a, b)| a.rank().cmp(&b.rank()))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| other.segments.len().cmp(&self.segments.len()))
+    }
+
+    /// What the radix tree holds for a route made of static and `:param` segments
+    fn key(&self) -> String {
+        self.segments
+            .iter()
+            .zip(self.path.split('/'))
+            .map(|(segment, pattern)| match segment {
+                Segment::Static(text) => escape_segment(text),
+                _ => pattern.to_owned(),
+            })
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    /// Params of a route already matched by the radix tree
+    fn params(&self, path: &str) -> HashMap<String, String> {
+        self.segments
+            .iter()
+            .zip(path.split('/'))
+            .filter_map(|(segment, value)| match segment {
+                Segment::Param(name, _) => Some((name.clone(), unescape_segment(value))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Matches the route against `path` checking the constraints, for the routes
+    /// the radix tree does not hold. Gives the params and what `*tail` captured
+    fn capture(
+        &self,
+        path: &str,
+        case_insensitive: bool,
+    ) -> Option<(HashMap<String, String>, Option<String>)> {
+        let mut params = HashMap::new();
+        let mut path_segments = path.split('/');
+        for segment in &self.segments {
+            let name = match segment {
+                Segment::CatchAll(name) => {
+                    let rest: Vec<String> = path_segments.map(unescape_segment).collect();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    let tail = rest.join("/");
+                    if !name.is_empty() {
+                        params.insert(name.clone(), tail.clone());
+                    }
+                    return Some((params, Some(tail)));
+                }
+                Segment::Param(name, _) => name,
+                Segment::Static(name) => name,
+            };
+
+            let path_segment = unescape_segment(path_segments.next()?);
+            match segment {
+                Segment::Param(..)
+                    if !path_segment.is_empty() && segment.accepts(&path_segment) =>
+                {
+                    params.insert(name.clone(), path_segment);
+                }
+                Segment::Static(_) if same_text(name, &path_segment, case_insensitive) => {}
+                _ => return None,
+            }
+        }
+        match path_segments.next() {
+            Some(_) => None,
+            None => Some((params, None)),
+        }
+    }
+}
+
+pub(crate) struct Found<'a, V> {
+    pub value: &'a V,
+    pub params: HashMap<String, String>,
+    pub tail: Option<String>,
+}
+
+#[derive(Clone, Default)]
+struct MethodRoutes {
+    // routes made of static and `:param` segments
+    node: Option<Node<usize>>,
+    // routes with a constrained param, the most specific first
+    constrained: Vec<usize>,
+    // routes ending with `*tail`, the most specific first
+    catch_alls: Vec<usize>,
+}
+
+/// One radix tree per HTTP method, all pointing into the same list of routes
+#[derive(Clone)]
+pub(crate) struct Router<V: Clone> {
+    methods: HashMap<String, MethodRoutes>,
+    routes: Vec<Route<V>>,
+    case_insensitive: bool,
+}
+
+impl<V: Clone> Router<V> {
+    pub fn new() -> Self {
+        Router {
+            methods: HashMap::new(),
+            routes: vec![],
+            case_insensitive: false,
+        }
+    }
+
+    /// Makes static segments match regardless of case, rebuilding the trees.
+    /// Panics if two routes become the same, like `/Users` and `/users`
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+        let routes = std::mem::take(&mut self.routes);
+        self.methods.clear();
+        for route in routes {
+            self.add(&route.method, &route.path, route.value);
+        }
+    }
+
+    // What the radix trees hold and are searched with
+    fn case_insensitive_key(&self, path: &str) -> String {
+        if self.case_insensitive {
+            path.to_lowercase()
+        } else {
+            path.to_owned()
+        }
+    }
+
+    pub fn add(&mut self, method: &str, path: &str, value: V) {
+        let segments = path
+            .split('/')
+            .map(Segment::parse)
+            .collect::<Result<Vec<Segment>, String>>()
+            .unwrap_or_else(|error| panic!("Invalid constraint in {:?}: {}", path, error));
+        let route = Route {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            segments,
+            value,
+        };
+        let misplaced_catch_all = route.segments[..route.segments.len() - 1]
+            .iter()
+            .any(|segment| segment.catch_all_name().is_some());
+        if misplaced_catch_all {
+            panic!("The catch-all segment must be the last one in {:?}", path);
+        }
+        let case_insensitive = self.case_insensitive;
+        if let Some(existing) = self
+            .routes
+            .iter()
+            .find(|r| r.conflicts_with(&route, case_insensitive))
+        {
+            panic!(
+                "Route {} {:?} conflicts with the already registered {:?}",
+                method, path, existing.path
+            );
+        }
+
+        let index = self.routes.len();
+        let key = self.case_insensitive_key(&route.key());
+        let method_routes = self.methods.entry(method.to_owned()).or_default();
+        let tried_in_turn = if route.is_catch_all() {
+            Some(&mut method_routes.catch_alls)
+        } else if route.is_constrained() {
+            Some(&mut method_routes.constrained)
+        } else {
+            None
+        };
+        if let Some(indexes) = tried_in_turn {
+            let routes = &self.routes;
+            let position = indexes
+                .iter()
+                .position(|other| route.cmp_specificity(&routes[*other]) == Ordering::Less)
+                .unwrap_or_else(|| indexes.len());
+            indexes.insert(position, index);
+        } else {
+            let node = method_routes.node.get_or_insert_with(create_root_node);
+            add(node, &key, index);
+        }
+        self.routes.push(route);
+    }
+
+    /// The most specific route matching `path`, see `cmp_specificity`
+    pub fn find(&self, method: &str, path: &str) -> Option<Found<'_, V>> {
+        let method_routes = self.methods.get(method)?;
+
+        let index = method_routes
+            .node
+            .as_ref()
+            .and_then(|node| find(node, &self.case_insensitive_key(path)).value.cloned());
+        let found = |index: usize, params, tail| Found {
+            value: &self.routes[index].value,
+            params,
+            tail,
+        };
+        if let Some(index) = index.filter(|index| self.routes[*index].is_static()) {
+            return Some(found(index, HashMap::new(), None));
+        }
+
+        // The first match of each list is the most specific one of the list
+        let captured = |index: &usize| {
+            self.routes[*index]
+                .capture(path, self.case_insensitive)
+                .map(|(params, tail)| (*index, params, tail))
+        };
+        let candidates = vec![
+            method_routes.constrained.iter().find_map(captured),
+            index.map(|index| (index, self.routes[index].params(path), None)),
+            method_routes.catch_alls.iter().find_map(captured),
+        ];
+        candidates
+            .into_iter()
+            .flatten()
+            .min_by(|(a, _, _), (b, _, _)| self.routes[*a].cmp_specificity(&self.routes[*b]))
+            .map(|(index, params, tail)| found(index, params, tail))
+    }
+
+    /// Methods, sorted, having a route matching `path`
+    pub fn allowed_methods(&self, path: &str) -> Vec<&str> {
+        let mut methods: Vec<&str> = self
+            .methods
+            .keys()
+            .filter(|method| self.find(method, path).is_some())
+            .map(|method| method.as_str())
+            .collect();
+        methods.sort();
+        methods
+    }
+
+    pub fn methods(&self) -> Vec<&str> {
+        let mut methods: Vec<&str> = self.methods.keys().map(|method| method.as_str()).collect();
+        methods.sort();
+        methods
+    }
+
+    /// (method, pattern, value) of every route, in registration order
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &str, &V)> {
+        self.routes
+            .iter()
+            .map(|route| (route.method.as_str(), route.path.as_str(), &route.value))
+    }
+
+    pub fn has_method(&self, method: &str) -> bool {
+        self.methods.contains_key(method)
+    }
+
+    pub fn optimize(&mut self) {
+        for method_routes in self.methods.values_mut() {
+            if let Some(node) = method_routes.node.take() {
+                method_routes.node = Some(optimize(node));
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UrlForError {
+    /// No route was registered with this name
+    UnknownRoute(String),
+    /// The route has a `:name` or `*name` segment with no value given
+    MissingParam(String),
+    /// The value given for a `:name(regex)` or `:name<type>` segment does not satisfy it
+    InvalidParam(String),
+}
+
+impl fmt::Display for UrlForError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlForError::UnknownRoute(name) => write!(f, "No route named {:?}", name),
+            UrlForError::MissingParam(name) => write!(f, "Missing value for param {:?}", name),
+            UrlForError::InvalidParam(name) => write!(f, "Invalid value for param {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for UrlForError {}
+
+/// Fills the `:name` and `*name` segments of `pattern` with the percent-encoded `params`.
+/// A catch-all value keeps its slashes
+pub(crate) fn build_path(pattern: &str, params: &[(&str, &str)]) -> Result<String, UrlForError> {
+    let value_of = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| UrlForError::MissingParam(name.to_owned()))
+    };
+
+    let mut segments = vec![];
+    for segment in pattern.split('/') {
+        let segment = Segment::parse(segment).expect("Patterns are checked when registered");
+        match &segment {
+            Segment::Static(text) => {
+                segments.push(utf8_percent_encode(text, PATH_SEGMENT).to_string())
+            }
+            Segment::Param(name, _) => {
+                let value = value_of(name)?;
+                if value.is_empty() || !segment.accepts(value) {
+                    return Err(UrlForError::InvalidParam(name.clone()));
+                }
+                segments.push(utf8_percent_encode(value, PATH_SEGMENT).to_string())
+            }
+            Segment::CatchAll(name) => {
+                for part in value_of(name)?.split('/') {
+                    segments.push(utf8_percent_encode(part, PATH_SEGMENT).to_string());
+                }
+            }
+        }
+    }
+    Ok(segments.join("/"))
+}
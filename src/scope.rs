@@ -0,0 +1,148 @@
+use crate::handler::{Endpoint, IntoEndpoint};
+use crate::middleware::Middleware;
+
+/// An endpoint with the middlewares of the scopes it was registered in, outermost first
+#[derive(Clone)]
+pub(crate) struct Scoped<T: Clone + Send + Sync> {
+    pub endpoint: Endpoint<T>,
+    pub middlewares: Vec<Box<dyn Middleware<T>>>,
+}
+
+impl<T: Clone + Send + Sync> Scoped<T> {
+    pub fn new(endpoint: Endpoint<T>) -> Self {
+        Scoped {
+            endpoint,
+            middlewares: vec![],
+        }
+    }
+
+    /// Puts `middlewares` in front of the ones already collected
+    pub fn within(&mut self, middlewares: &[Box<dyn Middleware<T>>]) {
+        self.middlewares.splice(0..0, middlewares.iter().cloned());
+    }
+}
+
+/// `prefix` joined with `path`, `/` standing for the prefix itself
+pub(crate) fn join_path(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if path == "/" && !prefix.is_empty() {
+        prefix.to_owned()
+    } else {
+        format!("{}{}", prefix, path)
+    }
+}
+
+/// A group of routes sharing a path prefix, middlewares and a not-found handler.
+/// Built by `App::scope`, its routes reach the `App` once the closure returns
+pub struct Scope<T: Clone + Send + Sync> {
+    prefix: String,
+    // (method, full path, handler)
+    pub(crate) routes: Vec<(String, String, Scoped<T>)>,
+    // (full prefix, handler)
+    pub(crate) not_found: Vec<(String, Scoped<T>)>,
+    // (route name, full path)
+    pub(crate) names: Vec<(String, String)>,
+    middlewares: Vec<Box<dyn Middleware<T>>>,
+}
+
+impl<T: Clone + Send + Sync> Scope<T> {
+    pub(crate) fn new(prefix: &str) -> Self {
+        Scope {
+            prefix: prefix.to_owned(),
+            routes: vec![],
+            not_found: vec![],
+            names: vec![],
+            middlewares: vec![],
+        }
+    }
+
+    pub fn get<M>(&mut self, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("GET", path, handler);
+    }
+
+    pub fn post<M>(&mut self, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("POST", path, handler);
+    }
+
+    pub fn put<M>(&mut self, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("PUT", path, handler);
+    }
+
+    pub fn delete<M>(&mut self, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("DELETE", path, handler);
+    }
+
+    pub fn patch<M>(&mut self, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("PATCH", path, handler);
+    }
+
+    pub fn head<M>(&mut self, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("HEAD", path, handler);
+    }
+
+    pub fn options<M>(&mut self, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route("OPTIONS", path, handler);
+    }
+
+    /// Registers `handler` for `method` at `path` below the scope prefix
+    pub fn route<M>(&mut self, method: &str, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.routes.push((
+            method.to_owned(),
+            join_path(&self.prefix, path),
+            Scoped::new(handler.into_endpoint()),
+        ));
+    }
+
+    /// Like `get`, naming the route for `App::url_for`
+    pub fn get_named<M>(&mut self, name: &str, path: &str, handler: impl IntoEndpoint<T, M>) {
+        self.route_named(name, "GET", path, handler);
+    }
+
+    /// Like `route`, naming the route for `App::url_for`
+    pub fn route_named<M>(
+        &mut self,
+        name: &str,
+        method: &str,
+        path: &str,
+        handler: impl IntoEndpoint<T, M>,
+    ) {
+        self.names
+            .push((name.to_owned(), join_path(&self.prefix, path)));
+        self.route(method, path, handler);
+    }
+
+    /// Runs `middleware` around the routes and the not-found handler of this scope,
+    /// nested scopes included, whenever they are registered
+    pub fn wrap(&mut self, middleware: impl Middleware<T> + 'static) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Replaces the not-found handler for the paths below the scope prefix
+    pub fn set_not_found<M>(&mut self, handler: impl IntoEndpoint<T, M>) {
+        let prefix = self.prefix.trim_end_matches('/').to_owned();
+        self.not_found.retain(|(p, _)| p != &prefix);
+        self.not_found
+            .push((prefix, Scoped::new(handler.into_endpoint())));
+    }
+
+    /// Nests a scope below this one: it inherits the prefix and the middlewares
+    pub fn scope(&mut self, prefix: &str, build: impl FnOnce(&mut Scope<T>)) {
+        let mut scope = Scope::new(&join_path(&self.prefix, prefix));
+        build(&mut scope);
+        scope.close();
+        self.routes.append(&mut scope.routes);
+        self.not_found.append(&mut scope.not_found);
+        self.names.append(&mut scope.names);
+    }
+
+    /// Applies the scope middlewares to everything registered in it
+    pub(crate) fn close(&mut self) {
+        let middlewares = std::mem::take(&mut self.middlewares);
+        for (_, _, handler) in self.routes.iter_mut() {
+            handler.within(&middlewares);
+        }
+        for (_, handler) in self.not_found.iter_mut() {
+            handler.within(&middlewares);
+        }
+    }
+}